@@ -0,0 +1,81 @@
+#![allow(dead_code)]
+
+/// `PT_LOAD` program header type
+const PT_LOAD: u32 = 1;
+
+/// Size of the ELF64 header
+const EHDR_SIZE: usize = 0x40;
+
+/// Size of a single ELF64 program header
+const PHDR_SIZE: usize = 0x38;
+
+/// A loadable (`PT_LOAD`) segment of an ELF
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    /// Offset of the segment contents in the file
+    pub offset: usize,
+
+    /// Virtual address the segment is loaded at
+    pub vaddr: usize,
+
+    /// Size of the segment contents in the file
+    pub file_size: usize,
+
+    /// Size of the segment in memory
+    pub mem_size: usize,
+
+    /// `p_flags` of the segment (`PF_X`, `PF_W`, `PF_R`)
+    pub flags: u32,
+}
+
+/// Read a little-endian `N` byte integer from `bytes` at `offset`
+fn read_le<const N: usize>(bytes: &[u8], offset: usize) -> Option<u64> {
+    let bytes: [u8; N] = bytes.get(offset..offset.checked_add(N)?)?
+        .try_into().ok()?;
+
+    let mut buf = [0u8; 8];
+    buf[..N].copy_from_slice(&bytes);
+    Some(u64::from_le_bytes(buf))
+}
+
+/// Parse the `PT_LOAD` segments of a little-endian ELF64 file.
+///
+/// Returns `None` if the file is malformed or not a little-endian ELF64, or
+/// if any segment's contents lie outside of the file.
+pub fn load_segments(elf: &[u8]) -> Option<Vec<Segment>> {
+    // Check the magic, the class (ELF64) and the data encoding (LE)
+    if elf.get(..6)? != b"\x7fELF\x02\x01" || elf.len() < EHDR_SIZE {
+        return None;
+    }
+
+    let phoff     = read_le::<8>(elf, 0x20)? as usize;
+    let phentsize = read_le::<2>(elf, 0x36)? as usize;
+    let phnum     = read_le::<2>(elf, 0x38)? as usize;
+    if phentsize < PHDR_SIZE {
+        return None;
+    }
+
+    let mut segments = Vec::new();
+    for ii in 0..phnum {
+        let phdr = phoff.checked_add(ii.checked_mul(phentsize)?)?;
+        if read_le::<4>(elf, phdr)? as u32 != PT_LOAD {
+            continue;
+        }
+
+        let segment = Segment {
+            flags:     read_le::<4>(elf, phdr + 0x04)? as u32,
+            offset:    read_le::<8>(elf, phdr + 0x08)? as usize,
+            vaddr:     read_le::<8>(elf, phdr + 0x10)? as usize,
+            file_size: read_le::<8>(elf, phdr + 0x20)? as usize,
+            mem_size:  read_le::<8>(elf, phdr + 0x28)? as usize,
+        };
+
+        // Make sure the segment contents are actually in the file
+        if segment.offset.checked_add(segment.file_size)? > elf.len() {
+            return None;
+        }
+        segments.push(segment);
+    }
+
+    Some(segments)
+}
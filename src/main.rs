@@ -1,6 +1,7 @@
+pub mod elf;
 pub mod mmu;
 
-use mmu::{Mmu, VAddr};
+use mmu::Mmu;
 
 /// State of the emulated system
 struct Emulator {
@@ -30,7 +31,7 @@ fn main() {
     let base = orig_emulator.memory.allocate(alloc).unwrap();
 
     let mut emulator = orig_emulator.fork();
-    for i in 0..10_000_000 {
+    for _ in 0..10_000_000 {
         emulator.memory.write(base, b"asdf").unwrap();
         emulator.memory.reset(&orig_emulator.memory);
     }
@@ -1,5 +1,13 @@
 #![allow(dead_code)]
 
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::elf;
+
 /// Memory is aligned to this base.
 const ALIGNMENT: usize = 0xf;
 
@@ -19,18 +27,383 @@ const PERM_WRITE: u8 = 1 << 0;
 const PERM_READ:  u8 = 1 << 1;
 /// Exec permission
 const PERM_EXEC:  u8 = 1 << 2;
+/// Read-after-write permission. Memory becomes readable once written to, so
+/// that reads of uninitialized memory fault
+const PERM_RAW:   u8 = 1 << 3;
+
+/// Size of the guard page placed below the stack of a thread
+const THREAD_GUARD_SIZE: usize = DIRTY_BLOCK_SIZE;
+
+/// Suggested size of the null guard set by `Mmu::set_null_guard()`
+pub const NULL_GUARD_SIZE: usize = DIRTY_BLOCK_SIZE;
 
+// Minidump format
+/// `MDMP` signature of a minidump header
+const MINIDUMP_SIGNATURE: u32 = 0x504d444d;
+/// Version of the minidump format
+const MINIDUMP_VERSION: u32 = 0xa793;
+/// Size of the minidump header
+const MINIDUMP_HEADER_SIZE: usize = 32;
+/// `MemoryInfoListStream` stream type
+const MEMORY_INFO_LIST_STREAM: u32 = 16;
+/// `Memory64ListStream` stream type
+const MEMORY64_LIST_STREAM: u32 = 9;
+/// Size of a single `MINIDUMP_MEMORY_INFO`
+const MEMORY_INFO_SIZE: usize = 48;
+/// `MEM_COMMIT` memory state
+const MEM_COMMIT: u32 = 0x1000;
+/// `MEM_PRIVATE` memory type
+const MEM_PRIVATE: u32 = 0x20000;
+/// Highest address a memory range of a minidump read into an MMU may reach,
+/// the memory being allocated all the way up to it
+const MINIDUMP_MAX_END: usize = 1 << 32;
+
+// Snapshot format
+/// `TFSN` magic of a snapshot header
+const SNAPSHOT_MAGIC: u32 = 0x4e534654;
+/// Version of the snapshot format, bumped on every incompatible change
+const SNAPSHOT_VERSION: u32 = 1;
+/// Size of the snapshot header (magic, version, memory size, allocation base)
+const SNAPSHOT_HEADER_SIZE: usize = 24;
+
+// Pagemap entry bit field
+/// Some byte of the page has a permission
+const PAGEMAP_PRESENT: u64 = 1 << 0;
+/// Some byte of the page is readable
+const PAGEMAP_READ:    u64 = 1 << 1;
+/// Some byte of the page is writable
+const PAGEMAP_WRITE:   u64 = 1 << 2;
+/// Some byte of the page is executable
+const PAGEMAP_EXEC:    u64 = 1 << 3;
 
 /// Memory permissions for a corresponding address
 #[repr(transparent)]
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Perm(pub u8);
 
 /// A guest Virtual Address
 #[repr(transparent)]
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct VAddr(pub usize);
 
+/// Byte order of multi-byte guest values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endian {
+    /// Least significant byte first
+    Little,
+
+    /// Most significant byte first
+    Big,
+}
+
+impl Endian {
+    /// Byte order of the host
+    #[cfg(target_endian = "little")]
+    pub const NATIVE: Endian = Endian::Little;
+
+    /// Byte order of the host
+    #[cfg(target_endian = "big")]
+    pub const NATIVE: Endian = Endian::Big;
+}
+
+/// Types which can be decoded from any bit pattern of the right size.
+///
+/// # Safety
+///
+/// Implementors must be non-zero sized and valid for every bit pattern,
+/// without any padding.
+pub unsafe trait Pod: Copy {}
+
+unsafe impl Pod for u8    {}
+unsafe impl Pod for u16   {}
+unsafe impl Pod for u32   {}
+unsafe impl Pod for u64   {}
+unsafe impl Pod for u128  {}
+unsafe impl Pod for usize {}
+unsafe impl Pod for i8    {}
+unsafe impl Pod for i16   {}
+unsafe impl Pod for i32   {}
+unsafe impl Pod for i64   {}
+unsafe impl Pod for i128  {}
+unsafe impl Pod for isize {}
+
+/// Memory layout of a thread created by `Mmu::setup_thread()`
+#[derive(Clone, Copy)]
+pub struct ThreadLayout {
+    /// Initial stack pointer (top of the stack)
+    pub sp: VAddr,
+
+    /// Base of the TLS block
+    pub tls_base: VAddr,
+
+    /// Base of the guard page below the stack
+    pub guard: VAddr,
+}
+
+/// Statistics accumulated over every `Mmu::reset()` since the last
+/// `Mmu::clear_reset_totals()`
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct ResetCumulative {
+    /// Number of resets performed
+    pub resets: usize,
+
+    /// Number of dirty blocks restored
+    pub blocks: usize,
+
+    /// Number of bytes restored
+    pub bytes: usize,
+
+    /// Number of copies issued to restore the blocks
+    pub copies: usize,
+}
+
+/// Contents of the dirty blocks of an MMU, created by `Mmu::extract_dirty()`
+#[derive(Clone)]
+pub struct DirtyPatch {
+    /// Dirty blocks as `(block index, memory, permissions)`
+    pub blocks: Vec<(usize, Vec<u8>, Vec<Perm>)>,
+}
+
+/// Writes performed on an MMU along with their data, in order. Created by
+/// `Mmu::journal_writes()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WriteJournal {
+    /// Journaled writes as `(address, bytes written)`
+    pub writes: Vec<(VAddr, Vec<u8>)>,
+}
+
+/// Permissions of the whole memory at some point, taken by
+/// `Mmu::snapshot_permissions()`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PermSnapshot {
+    /// Permission of every address
+    perms: Vec<Perm>,
+}
+
+/// A circular buffer mapped by `Mmu::map_ring()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ring {
+    /// Base of the ring
+    base: VAddr,
+
+    /// Size of the ring in bytes, never 0
+    size: usize,
+}
+
+impl Ring {
+    /// Describe the `size` byte ring buffer at `base`, which can't be empty
+    pub fn new(base: VAddr, size: usize) -> Result<Self, MmuError> {
+        if size == 0 {
+            return Err(MmuError::InvalidArgument);
+        }
+        Ok(Ring { base, size })
+    }
+
+    /// Returns the base of the ring
+    pub fn base(&self) -> VAddr {
+        self.base
+    }
+
+    /// Returns the size of the ring in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Split the `len` long access at `offset` (modulo the ring size) into
+    /// the `(address, offset into the access, length)` pieces which don't
+    /// wrap around
+    fn pieces(&self, offset: usize,
+              len: usize) -> impl Iterator<Item = (VAddr, usize, usize)> + '_ {
+        let mut cursor = offset % self.size;
+        let mut done   = 0;
+        std::iter::from_fn(move || {
+            if done >= len {
+                return None;
+            }
+            let piece = (self.size - cursor).min(len - done);
+            let item  = (VAddr(self.base.0 + cursor), done, piece);
+            cursor = 0;
+            done  += piece;
+            Some(item)
+        })
+    }
+}
+
+/// Kind of a memory access
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    /// Data read, requires `PERM_READ`
+    Read,
+
+    /// Data write, requires `PERM_WRITE`
+    Write,
+
+    /// Instruction fetch, requires `PERM_EXEC`
+    Exec,
+}
+
+impl AccessKind {
+    /// Returns the permission bit required by this kind of access
+    fn perm(self) -> u8 {
+        match self {
+            AccessKind::Read  => PERM_READ,
+            AccessKind::Write => PERM_WRITE,
+            AccessKind::Exec  => PERM_EXEC,
+        }
+    }
+}
+
+/// Why an access faulted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultReason {
+    /// The address lies outside of the memory
+    OutOfBounds,
+
+    /// The address has no permissions at all
+    Unmapped,
+
+    /// The address is mapped but lacks the required permission
+    PermissionDenied,
+}
+
+/// Description of a faulting memory access
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PageFault {
+    /// First faulting address of the access
+    pub addr: VAddr,
+
+    /// Kind of the access
+    pub kind: AccessKind,
+
+    /// Why the access faulted
+    pub reason: FaultReason,
+
+    /// Index of the dirty block containing `addr`
+    pub block: usize,
+}
+
+/// Errors of fallible MMU operations
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MmuError {
+    /// The `len` long range at `addr` doesn't lie within the memory
+    OutOfBounds { addr: VAddr, len: usize },
+
+    /// The byte at `addr` lacks the `missing` permission bits
+    PermissionDenied { addr: VAddr, missing: Perm },
+
+    /// There's no memory left to satisfy an allocation
+    OutOfMemory,
+
+    /// An address or size computation overflowed
+    Overflow,
+
+    /// There is no (matching) allocation at `addr`
+    NotAllocated { addr: VAddr },
+
+    /// The write at `addr` touches blocks locked by `Mmu::lock_region()`
+    Locked { addr: VAddr },
+
+    /// The arguments don't describe a valid operation (e.g. a bitfield wider
+    /// than 64 bits)
+    InvalidArgument,
+
+    /// The write at `addr` would copy more blocks out of the copy-on-write
+    /// parent than `Mmu::set_fork_budget()` allows
+    BudgetExceeded { addr: VAddr },
+
+    /// There's no baseline attached through `Mmu::attach_baseline()`
+    NoBaseline,
+
+    /// The linked structure loops back onto the node at `addr`
+    Cycle { addr: VAddr },
+}
+
+/// How `Mmu::modify_permissions()` combines the given permissions with the
+/// current ones
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PermOp {
+    /// Overwrite the current permissions
+    Replace,
+
+    /// Set the given bits, keeping the rest
+    Add,
+
+    /// Clear the given bits, keeping the rest
+    Remove,
+}
+
+/// Policy on combining executable memory with other permissions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WxPolicy {
+    /// No byte may be both writable and executable
+    Exclusive,
+
+    /// No byte may be executable at all
+    NoExec,
+}
+
+/// Reasons for an ELF not matching the memory it was loaded into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The ELF couldn't be parsed
+    Malformed,
+
+    /// A segment lies outside of the memory, starting at this address
+    OutOfBounds(VAddr),
+
+    /// Memory differs from the file contents, first at this address
+    Mismatch(VAddr),
+}
+
+/// A region whose permissions differ from the layout given to
+/// `Mmu::assert_layout()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LayoutMismatch {
+    /// The expected region as `(base, size)`
+    pub range: (VAddr, usize),
+
+    /// The first address in the region with different permissions
+    pub addr: VAddr,
+
+    /// The permissions the region was expected to have
+    pub expected: Perm,
+
+    /// The actual permissions at `addr` (none if out of bounds)
+    pub actual: Perm,
+}
+
+/// A rollback point created by `Mmu::checkpoint()` or `Mmu::push_overlay()`
+struct Checkpoint {
+    /// Name of the checkpoint, `None` for overlays
+    name: Option<String>,
+
+    /// Contents of the blocks modified since the checkpoint, as they were at
+    /// the checkpoint, keyed by block index
+    saved: BTreeMap<usize, (Vec<u8>, Vec<Perm>)>,
+
+    /// `Mmu::alloc_base` at the checkpoint
+    alloc_base: VAddr,
+
+    /// `Mmu::allocations` at the checkpoint
+    allocations: BTreeMap<VAddr, Allocation>,
+
+    /// `Mmu::run_allocs` at the checkpoint
+    run_allocs: BTreeMap<VAddr, usize>,
+}
+
+/// Metadata of a region handed out by `Mmu::allocate()`
+#[derive(Clone)]
+struct Allocation {
+    /// Requested size of the allocation
+    size: usize,
+
+    /// Sub-allocations carved out of this allocation as `(base, size)`
+    children: Vec<(VAddr, usize)>,
+
+    /// `[from, to)` range of memory taken from the bump allocator, including
+    /// the alignment padding before the allocation and the guard gap after
+    reserved: (VAddr, VAddr),
+}
 
 /// Returns the number `num` aligned to `self.alignment`
 #[inline(always)]
@@ -38,14 +411,126 @@ pub fn align(num: usize) -> usize {
     (num + ALIGNMENT) & !ALIGNMENT
 }
 
+/// Returns a bitmap of `len` zeroed atomic words
+fn atomic_bitmap(len: usize) -> Vec<AtomicU64> {
+    (0..len).map(|_| AtomicU64::new(0)).collect()
+}
+
+/// Returns `len` permissions of `Perm(0)`, allocated as zeroed memory so that
+/// the pages are only backed once they're actually written to
+fn zeroed_perms(len: usize) -> Vec<Perm> {
+    let mut perms = std::mem::ManuallyDrop::new(vec![0u8; len]);
+
+    // SAFETY: `Perm` is a `repr(transparent)` `u8`, so the allocation has the
+    // same layout and every byte is a valid `Perm`
+    unsafe {
+        Vec::from_raw_parts(perms.as_mut_ptr() as *mut Perm, perms.len(),
+                            perms.capacity())
+    }
+}
+
+/// Returns the address of the first byte covering a bitfield, the number of
+/// bytes covering it and the shift of the bitfield within those bytes
+fn bitfield_span(addr: VAddr, bit_offset: usize, bit_len: usize,
+                 endian: Endian) -> Option<(VAddr, usize, usize)> {
+    if bit_len == 0 || bit_len > 64 {
+        return None;
+    }
+
+    let start  = addr.0.checked_add(bit_offset / 8)?;
+    let skip   = bit_offset % 8;
+    let nbytes = (skip + bit_len).div_ceil(8);
+    let shift  = match endian {
+        Endian::Little => skip,
+        Endian::Big    => nbytes * 8 - skip - bit_len,
+    };
+    Some((VAddr(start), nbytes, shift))
+}
+
+/// Interpret the first `nbytes` of `buf` as an integer in `endian` byte order
+fn bitfield_decode(buf: &[u8; 16], nbytes: usize, endian: Endian) -> u128 {
+    match endian {
+        Endian::Little => u128::from_le_bytes(*buf),
+        Endian::Big    => u128::from_be_bytes(*buf) >> (128 - nbytes * 8),
+    }
+}
+
+/// Returns the combined length of the buffers of a vectored access
+fn vectored_len(mut lens: impl Iterator<Item = usize>)
+        -> Result<usize, MmuError> {
+    lens.try_fold(0usize, |acc, len| acc.checked_add(len))
+        .ok_or(MmuError::Overflow)
+}
+
+/// Returns the Windows page protection closest to `perm`.
+///
+/// Windows has no write-only protection, so those regions become read-write.
+fn perm_to_protect(perm: Perm) -> u32 {
+    let exec  = (perm.0 & PERM_EXEC)  != 0;
+    let write = (perm.0 & PERM_WRITE) != 0;
+    let read  = (perm.0 & PERM_READ)  != 0;
+    match (exec, write, read) {
+        (true,  true,  _)     => 0x40, // PAGE_EXECUTE_READWRITE
+        (true,  false, true)  => 0x20, // PAGE_EXECUTE_READ
+        (true,  false, false) => 0x10, // PAGE_EXECUTE
+        (false, true,  _)     => 0x04, // PAGE_READWRITE
+        (false, false, true)  => 0x02, // PAGE_READONLY
+        (false, false, false) => 0x01, // PAGE_NOACCESS
+    }
+}
+
+/// Returns the permissions for a Windows page protection
+fn protect_to_perm(protect: u32) -> Perm {
+    // Ignore the `PAGE_GUARD`/`PAGE_NOCACHE`/... modifiers
+    Perm(match protect & 0xff {
+        0x40 | 0x80 => PERM_EXEC | PERM_WRITE | PERM_READ,
+        0x20        => PERM_EXEC | PERM_READ,
+        0x10        => PERM_EXEC,
+        0x04 | 0x08 => PERM_WRITE | PERM_READ,
+        0x02        => PERM_READ,
+        _           => 0,
+    })
+}
+
+/// Read a little-endian `u32` from `bytes` at `offset`
+fn minidump_u32(bytes: &[u8], offset: usize) -> io::Result<u32> {
+    offset.checked_add(4).and_then(|end| bytes.get(offset..end))
+        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "Truncated minidump"))
+}
+
+/// Read a little-endian `u64` from `bytes` at `offset`
+fn minidump_u64(bytes: &[u8], offset: usize) -> io::Result<u64> {
+    Ok(minidump_u32(bytes, offset)? as u64 |
+       (minidump_u32(bytes, offset + 4)? as u64) << 32)
+}
+
+/// Make sure the table of `entries` entries of `entry_size` bytes at `offset`
+/// lies within `bytes`, returning the offset right past it
+fn minidump_table(bytes: &[u8], offset: usize, entries: usize,
+                  entry_size: usize) -> io::Result<usize> {
+    entries.checked_mul(entry_size)
+        .and_then(|size| offset.checked_add(size))
+        .filter(|&end| end <= bytes.len())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                                      "Minidump table outside of the dump"))
+}
+
+/// Hook invoked by `Mmu::fetch()`, see `Mmu::set_on_first_fetch()`
+type FetchHook = Box<dyn FnMut(VAddr) + Send + Sync>;
+
 /// Memory space of an emulator
 pub struct Mmu {
     /// Guest memory address space
     memory: Vec<u8>,
 
     /// Permissions of the corresponding memory.
-    /// This doubles the memory footprint, I am aware
-    pub permissions: Vec<Perm>,
+    /// This doubles the memory footprint, I am aware.
+    ///
+    /// Blocks of a copy-on-write fork which weren't copied from the parent
+    /// yet hold garbage here, see `fork_cow()` and `permissions()`
+    permissions: Vec<Perm>,
 
     /// Indexes into `dirty_bitmap`
     dirty_indexes: Vec<usize>,
@@ -55,8 +540,100 @@ pub struct Mmu {
 
     /// Base `VAddr` of the next allocation
     alloc_base: VAddr,
+
+    /// Live allocations keyed by their base address
+    allocations: BTreeMap<VAddr, Allocation>,
+
+    /// Sizes of the allocations made since the last fork or reset
+    run_allocs: BTreeMap<VAddr, usize>,
+
+    /// Region names as `(size, name)` keyed by their base address
+    labels: BTreeMap<VAddr, (usize, String)>,
+
+    /// Stack of checkpoints and overlays, most recent last
+    checkpoints: Vec<Checkpoint>,
+
+    /// Baseline restored by `reset_self()`
+    baseline: Option<Arc<Mmu>>,
+
+    /// Statistics accumulated over all resets
+    cumulative: ResetCumulative,
+
+    /// Mask ANDed into the permissions of every access
+    access_mask: Perm,
+
+    /// Ranges written within the current `record_writes()` scope
+    write_log: Option<Vec<(VAddr, usize)>>,
+
+    /// A bitmap tracking blocks which have been fetched from, in 64 bit words
+    /// so that `fetch()` can update it through a shared reference
+    exec_bitmap: Vec<AtomicU64>,
+
+    /// Hook invoked with the base of a block the first time it's fetched from
+    fetch_hook: Option<Mutex<FetchHook>>,
+
+    /// Whether `PERM_EXEC` can only be granted through `finalize_code()`
+    deny_exec: bool,
+
+    /// Whether writes clear `PERM_EXEC` from the written bytes
+    strict_wx: bool,
+
+    /// Size of the region at address 0 which never gets any permissions
+    null_guard: usize,
+
+    /// Size of the unmapped gap left after every allocation
+    alloc_guard: usize,
+
+    /// Whether to verify the whole memory against the baseline after resets
+    full_verify: bool,
+
+    /// Whether permissions are checked on accesses
+    enforce_perms: bool,
+
+    /// Permissions given to fresh allocations by `allocate`
+    alloc_perm: Perm,
+
+    /// A bitmap tracking blocks locked by `lock_region()`, which are never
+    /// written to nor restored
+    locked_blocks: Vec<u128>,
+
+    /// Secondary dirty tracking as the cache line size and the indexes of
+    /// the lines dirtied since the last reset, if enabled
+    cache_lines: Option<(usize, BTreeSet<usize>)>,
+
+    /// Writes journaled within the current `journal_writes()` scope
+    journal: Option<WriteJournal>,
+
+    /// A bitmap tracking blocks which are left as they are by resets
+    persistent: Vec<u128>,
+
+    /// A bitmap tracking blocks written to since the last
+    /// `clear_working_set()`, regardless of resets
+    working_set: Vec<u128>,
+
+    /// Software breakpoints as `address -> (original byte, trap byte)`
+    breakpoints: BTreeMap<VAddr, (u8, u8)>,
+
+    /// Parent of a copy-on-write fork, holding the blocks not copied yet
+    cow_parent: Option<Arc<Mmu>>,
+
+    /// A bitmap tracking blocks copied from `cow_parent`
+    cow_blocks: Vec<u128>,
+
+    /// A bitmap tracking blocks whose copy was charged against `fork_budget`
+    cow_charged: Vec<u128>,
+
+    /// Number of bytes which may still be copied from `cow_parent`, if
+    /// limited
+    fork_budget: Option<usize>,
 }
 
+// A base MMU is shared between the fuzzing threads, each owning its own fork
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Mmu>();
+};
+
 impl Mmu {
 /// Create a new `size` long memory space.
 ///
@@ -86,187 +663,5184 @@ impl Mmu {
             dirty_indexes: Vec::with_capacity(size / DIRTY_BLOCK_SIZE + 1),
             dirty_bitmap:  vec![0; dirty_bm_size],
             alloc_base:    VAddr(0x0),
+            allocations:   BTreeMap::new(),
+            run_allocs:    BTreeMap::new(),
+            labels:        BTreeMap::new(),
+            checkpoints:   Vec::new(),
+            baseline:      None,
+            cumulative:    ResetCumulative::default(),
+            access_mask:   Perm(!0),
+            write_log:     None,
+            exec_bitmap:   atomic_bitmap(dirty_bm_size * 2),
+            fetch_hook:    None,
+            deny_exec:     false,
+            strict_wx:     false,
+            null_guard:    0,
+            alloc_guard:   0,
+            full_verify:   false,
+            enforce_perms: true,
+            alloc_perm:    Perm(PERM_RAW | PERM_WRITE),
+            locked_blocks: vec![0; dirty_bm_size],
+            cache_lines:   None,
+            journal:       None,
+            persistent:    vec![0; dirty_bm_size],
+            working_set:   vec![0; dirty_bm_size],
+            breakpoints:   BTreeMap::new(),
+            cow_parent:    None,
+            cow_blocks:    vec![0; dirty_bm_size],
+            cow_charged:   vec![0; dirty_bm_size],
+            fork_budget:   None,
+        }
+    }
+
+    /// Grow the memory space by `additional` bytes (aligned to `0xf`). The new
+    /// tail has no permissions.
+    ///
+    /// Forks don't grow along, so the baseline of a fork should only be grown
+    /// before forking from it.
+    pub fn grow(&mut self, additional: usize) -> Result<(), MmuError> {
+        self.grow_with_perm(additional, Perm(0))
+    }
+
+    /// Grow the memory space by `additional` bytes (aligned to `0xf`), setting
+    /// the permissions of the new tail to `perm`
+    pub fn grow_with_perm(&mut self, additional: usize,
+                          perm: Perm) -> Result<(), MmuError> {
+        let old_size = self.memory.len();
+        let new_size = old_size.checked_add(additional)
+            .and_then(|x| x.checked_add(ALIGNMENT))
+            .ok_or(MmuError::Overflow)? & !ALIGNMENT;
+        let dirty_bm_size = new_size / DIRTY_BLOCK_SIZE / DBE_BITS + 1;
+
+        // The parent of a copy-on-write fork doesn't grow along, so the
+        // partial last block is copied and the new tail is owned outright
+        self.materialize(old_size.saturating_sub(1), old_size)?;
+        self.memory.resize(new_size, 0);
+        self.permissions.resize(new_size, Perm(0));
+        self.dirty_bitmap.resize(dirty_bm_size, 0);
+        self.exec_bitmap.resize_with(dirty_bm_size * 2, AtomicU64::default);
+        self.locked_blocks.resize(dirty_bm_size, 0);
+        self.persistent.resize(dirty_bm_size, 0);
+        self.working_set.resize(dirty_bm_size, 0);
+        self.cow_blocks.resize(dirty_bm_size, 0);
+        self.cow_charged.resize(dirty_bm_size, 0);
+        for block in old_size.div_ceil(DIRTY_BLOCK_SIZE)..
+                new_size.div_ceil(DIRTY_BLOCK_SIZE) {
+            self.cow_blocks[block / DBE_BITS] |= 1 << (block % DBE_BITS);
         }
+
+        self.set_permissions(VAddr(old_size), new_size - old_size, perm)
     }
 
     /// Fork the memory state of the current MMU, clearing all dirty bits.
     pub fn fork(&self) -> Self {
+        let len = self.memory.len();
+        self.fork_with(self.bytes(0, len).into_owned(),
+                       self.perms(0, len).into_owned())
+    }
+
+    /// Fork the memory state of the shared `parent` MMU without copying its
+    /// memory, clearing all dirty bits.
+    ///
+    /// The fork looks blocks up in `parent` until they're first modified, at
+    /// which point only that block is copied over. Resetting the fork against
+    /// `parent` then just drops the copied blocks again, rather than copying
+    /// them back.
+    ///
+    /// `parent` is never modified through its forks, so a single base can be
+    /// shared by any number of threads, each forking and resetting its own.
+    /// The fork keeps `parent` alive, which is why it has to be an `Arc`.
+    pub fn fork_cow(self: &Arc<Self>) -> Self {
+        let len = self.memory.len();
+        let mut fork = self.fork_with(vec![0; len], zeroed_perms(len));
+        fork.cow_parent = Some(Arc::clone(self));
+        fork
+    }
+
+    /// Limit the number of bytes a copy-on-write fork may copy out of its
+    /// parent to `budget`, or lift the limit.
+    ///
+    /// Every block copied on a write or a permission change is charged against
+    /// the budget, and operations which would have to copy more than what's
+    /// left fail with `MmuError::BudgetExceeded` without modifying anything.
+    /// Charged blocks dropped again by resets against the parent are refunded.
+    ///
+    /// The budget only covers this fork, it isn't shared with or inherited by
+    /// its own forks.
+    pub fn set_fork_budget(&mut self, budget: Option<usize>) {
+        self.fork_budget = budget;
+    }
+
+    /// Returns the number of bytes left in the budget set by
+    /// `set_fork_budget()`, if any
+    pub fn fork_budget(&self) -> Option<usize> {
+        self.fork_budget
+    }
+
+    /// Make sure the budget left covers the blocks in `[from, to)` which
+    /// still have to be copied from the copy-on-write parent
+    fn check_fork_budget(&self, addr: VAddr, from: usize,
+                         to: usize) -> Result<(), MmuError> {
+        self.check_fork_budget_all([(addr, from, to)])
+    }
+
+    /// Make sure the budget left covers the blocks in all of the
+    /// `(addr, from, to)` ranges which still have to be copied from the
+    /// copy-on-write parent, counting blocks shared by ranges once. The error
+    /// holds the `addr` of the range going over the budget.
+    fn check_fork_budget_all(&self,
+                             ranges: impl IntoIterator<Item = (VAddr, usize,
+                                                               usize)>)
+            -> Result<(), MmuError> {
+        let Some(left) = self.fork_budget else { return Ok(()) };
+
+        let mut counted = BTreeSet::new();
+        let mut needed  = 0;
+        for (addr, from, to) in ranges {
+            if from >= to {
+                continue;
+            }
+
+            for block in from / DIRTY_BLOCK_SIZE..=(to - 1) / DIRTY_BLOCK_SIZE {
+                if !self.is_owned(block) && counted.insert(block) {
+                    let (from, to) = self.block_range(block);
+                    needed += to - from;
+                }
+            }
+            if needed > left {
+                return Err(MmuError::BudgetExceeded { addr });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of blocks a copy-on-write fork holds its own copy
+    /// of, 0 for any other MMU
+    pub fn cow_copied_blocks(&self) -> usize {
+        if self.cow_parent.is_none() {
+            return 0;
+        }
+        self.cow_blocks.iter().map(|x| x.count_ones() as usize).sum()
+    }
+
+    /// Fork the state of the current MMU onto `memory` and `permissions`
+    fn fork_with(&self, memory: Vec<u8>, permissions: Vec<Perm>) -> Self {
         Self {
-            memory:        self.memory.clone(),
-            permissions:   self.permissions.clone(),
+            memory,
+            permissions,
             dirty_indexes: Vec::with_capacity(self.dirty_indexes.capacity()),
             dirty_bitmap:  vec![0; self.dirty_bitmap.len()],
             alloc_base:    self.alloc_base,
+            allocations:   self.allocations.clone(),
+            run_allocs:    BTreeMap::new(),
+            labels:        self.labels.clone(),
+            checkpoints:   Vec::new(),
+            baseline:      None,
+            cumulative:    ResetCumulative::default(),
+            access_mask:   self.access_mask,
+            write_log:     None,
+            exec_bitmap:   atomic_bitmap(self.exec_bitmap.len()),
+            fetch_hook:    None,
+            deny_exec:     self.deny_exec,
+            strict_wx:     self.strict_wx,
+            null_guard:    self.null_guard,
+            alloc_guard:   self.alloc_guard,
+            full_verify:   self.full_verify,
+            enforce_perms: self.enforce_perms,
+            alloc_perm:    self.alloc_perm,
+            locked_blocks: self.locked_blocks.clone(),
+            cache_lines:   self.cache_lines.as_ref()
+                .map(|(line, _)| (*line, BTreeSet::new())),
+            journal:       None,
+            persistent:    self.persistent.clone(),
+            working_set:   vec![0; self.working_set.len()],
+            breakpoints:   self.breakpoints.clone(),
+            cow_parent:    None,
+            cow_blocks:    vec![0; self.cow_blocks.len()],
+            cow_charged:   vec![0; self.cow_blocks.len()],
+            fork_budget:   None,
         }
     }
 
     /// Restore the memory state (dirty blocks) of the current MMU to the state
     /// of the `other` MMU.
+    ///
+    /// Adjacent dirty blocks are restored together with a single copy. A
+    /// copy-on-write fork reset against its parent drops its copies of the
+    /// dirty blocks instead. Memory grown past the end of `other` is zeroed
+    /// out and made inaccessible.
     pub fn reset(&mut self, other: &Mmu) {
-        for &dirty_idx in &self.dirty_indexes {
-            let from = dirty_idx * DIRTY_BLOCK_SIZE;
-            let to   = (dirty_idx + 1) * DIRTY_BLOCK_SIZE;
+        self.keep_persistent();
+        self.lift_breakpoints();
+        self.dirty_indexes.sort_unstable();
+        let parent = self.cow_parent.as_deref()
+            .is_some_and(|parent| std::ptr::eq(parent, other));
+
+        let mut ii = 0;
+        while ii < self.dirty_indexes.len() {
+            // Find the run of adjacent dirty blocks starting at this one
+            let first = self.dirty_indexes[ii];
+            let mut last = first;
+            while self.dirty_indexes.get(ii + 1) == Some(&(last + 1)) {
+                last += 1;
+                ii   += 1;
+            }
+            ii += 1;
+
+            // The last block may only partially fit in memory
+            let (from, _) = self.block_range(first);
+            let (_, to)   = self.block_range(last);
 
             // Reset the bitmap
-            self.dirty_bitmap[dirty_idx / DBE_BITS] = 0;
+            for dirty_idx in first..=last {
+                self.dirty_bitmap[dirty_idx / DBE_BITS] &=
+                    !(1 << (dirty_idx % DBE_BITS));
+            }
 
-            // Reset the memory
-            self.memory[from..to]
-                .copy_from_slice(&other.memory[from..to]);
+            // Look the blocks up in the parent again
+            if parent {
+                for dirty_idx in first..=last {
+                    // Blocks the fork grew into aren't in the parent
+                    let (from, to) = self.block_range(dirty_idx);
+                    if to > other.memory.len() {
+                        self.restore_range(other, from, to);
+                        continue;
+                    }
 
-            // Reset the permissions
-            self.permissions[from..to]
-                .copy_from_slice(&other.permissions[from..to]);
+                    // Refund the copy dropped here, if it was charged
+                    let (idx, bit) =
+                        (dirty_idx / DBE_BITS, dirty_idx % DBE_BITS);
+                    if self.cow_charged[idx] & (1 << bit) != 0 {
+                        if let Some(left) = &mut self.fork_budget {
+                            *left += to - from;
+                        }
+                    }
+                    self.cow_blocks[idx]  &= !(1 << bit);
+                    self.cow_charged[idx] &= !(1 << bit);
+                }
+                continue;
+            }
+
+            self.restore_range(other, from, to);
+            self.cumulative.bytes  += to - from;
+            self.cumulative.copies += 1;
         }
+        self.cumulative.blocks += self.dirty_indexes.len();
+        self.cumulative.resets += 1;
         self.dirty_indexes.clear();
-    }
-
-    /// Allocate a region in memory
-    pub fn allocate(&mut self, size: usize) -> Option<VAddr> {
-        // Update the allocation base
-        let cur_base  = VAddr(self.alloc_base.0);
-        let next_base = VAddr(cur_base.0.checked_add(align(size))?);
+        self.exec_bitmap.iter_mut().for_each(|x| *x.get_mut() = 0);
+        if let Some((_, lines)) = &mut self.cache_lines {
+            lines.clear();
+        }
+        self.run_allocs.clear();
+        self.checkpoints.clear();
+        self.install_breakpoints(Some(other));
+        self.verify_reset_idempotent();
 
-        // Don't allocate OOM
-        if next_base.0 > self.memory.len() {
-            return None;
+        // Make sure restoring the dirty blocks restored everything, except
+        // for the blocks and breakpoints which resets leave alone on purpose.
+        // Memory past the end of `other` must be zeroed out and inaccessible
+        if self.full_verify {
+            let len = self.memory.len();
+            let other_len = other.memory.len().min(len);
+            let (memory, perms) = (self.bytes(0, len), self.perms(0, len));
+            let (other_memory, other_perms) =
+                (other.bytes(0, other_len), other.perms(0, other_len));
+            let diverged = (0..len).find(|&addr| {
+                let block = addr / DIRTY_BLOCK_SIZE;
+                let (byte, perm) = other_memory.get(addr)
+                    .zip(other_perms.get(addr))
+                    .map_or((0, Perm(0)), |(&byte, &perm)| (byte, perm));
+                !self.is_locked(block) && !self.is_persistent(block) &&
+                    !self.breakpoints.contains_key(&VAddr(addr)) &&
+                    (memory[addr] != byte || perms[addr] != perm)
+            });
+            if let Some(addr) = diverged {
+                panic!("Reset memory diverges from the baseline at {:#x}",
+                       addr);
+            }
         }
+    }
 
-        // Mark the memory as writable
-        self.set_permissions(cur_base, size, Perm(PERM_WRITE))?;
+    /// Restore the memory and permissions in `from..to` to those of `other`.
+    ///
+    /// Memory past the end of `other` (grown since forking) is zeroed out and
+    /// made inaccessible.
+    fn restore_range(&mut self, other: &Mmu, from: usize, to: usize) {
+        let split = to.min(other.memory.len()).max(from);
+        self.materialize_uncharged(from, to);
+        if from < split {
+            self.memory[from..split]
+                .copy_from_slice(&other.bytes(from, split));
+            self.permissions[from..split]
+                .copy_from_slice(&other.perms(from, split));
+        }
+        self.memory[split..to].fill(0);
+        self.permissions[split..to].fill(Perm(0));
+    }
 
-        self.alloc_base = next_base;
-        Some(cur_base)
+    /// Verify (or stop verifying) that every `reset()` restores the whole
+    /// memory to the baseline, panicking on the first divergent address.
+    ///
+    /// This compares the entire address space on every reset, so it's very
+    /// slow and only meant for catching dirty tracking bugs.
+    pub fn set_debug_full_verify(&mut self, on: bool) {
+        self.full_verify = on;
     }
 
-    /// Set the permissions of a `size` long memory block starting from `addr`
-    /// to `perm`
-    pub fn set_permissions(&mut self, addr: VAddr,
-                           size: usize, perm: Perm) -> Option<()> {
-        self.permissions.get_mut(addr.0..addr.0.checked_add(size)?)?
-            .iter_mut().for_each(|x| x.0 = perm.0);
-        Some(())
+    /// Returns the first address whose memory or permissions differ between
+    /// the current MMU and `other`
+    fn first_divergence(&self, other: &Mmu) -> Option<VAddr> {
+        let len = self.memory.len().min(other.memory.len());
+        self.bytes(0, len).iter().zip(other.bytes(0, len).iter())
+            .zip(self.perms(0, len).iter().zip(other.perms(0, len).iter()))
+            .position(|((a, b), (pa, pb))| a != b || pa != pb)
+            .map(VAddr)
     }
 
-    /// Write bytes from `buf` to memory at `addr`.
-    /// The resulting bytes are set to be readable (`PERM_READ`)
-    pub fn write(&mut self, addr: VAddr, buf: &[u8]) -> Option<()> {
-        let from = addr.0;
-        let to   = addr.0.checked_add(buf.len())?;
+    /// Returns the contiguous ranges as `(address, length)` where the memory or
+    /// the permissions of the current MMU differ from those of `other`.
+    ///
+    /// Only the dirty blocks are compared, so `other` should be the MMU this
+    /// one was forked from or last reset to.
+    pub fn diff(&self, other: &Mmu) -> Vec<(VAddr, usize)> {
+        let mut blocks = self.dirty_indexes.clone();
+        blocks.sort_unstable();
+
+        let mut ranges: Vec<(VAddr, usize)> = Vec::new();
+        for block in blocks {
+            let (from, to) = self.block_range(block);
+            let to = to.min(other.memory.len());
+            if from >= to {
+                continue;
+            }
 
-        let perms = self.permissions.get_mut(from..to)?;
+            let (memory, perms) = (self.bytes(from, to), self.perms(from, to));
+            let (other_memory, other_perms) =
+                (other.bytes(from, to), other.perms(from, to));
+            for off in 0..to - from {
+                if memory[off] == other_memory[off] &&
+                        perms[off] == other_perms[off] {
+                    continue;
+                }
 
-        // Check that we can write to memory
-        if perms.iter().any(|x| (x.0 & PERM_WRITE) == 0) {
-            return None;
+                // Extend the last range if it ends right here
+                let addr = from + off;
+                match ranges.last_mut() {
+                    Some((base, len)) if base.0 + *len == addr => *len += 1,
+                    _ => ranges.push((VAddr(addr), 1)),
+                }
+            }
         }
+        ranges
+    }
 
-        // Write the buffer to memory
-        self.memory.get_mut(from..to)?.copy_from_slice(buf);
+    /// Zero out the memory and clear the permissions of all dirty blocks,
+    /// without needing a baseline to restore them from
+    pub fn reset_to_zero(&mut self) {
+        self.keep_persistent();
+        self.lift_breakpoints();
+        for &dirty_idx in &self.dirty_indexes {
+            let (from, to) = self.block_range(dirty_idx);
 
-        // Track the dirty memory
-        let dirty_start = addr.0 / DIRTY_BLOCK_SIZE;
-        let dirty_end   = to / DIRTY_BLOCK_SIZE;
-        for dirty_block in dirty_start..=dirty_end {
-            let idx = dirty_start / DBE_BITS;
-            let bit = dirty_start % DBE_BITS;
+            // Reset the bitmap
+            self.dirty_bitmap[dirty_idx / DBE_BITS] &=
+                !(1 << (dirty_idx % DBE_BITS));
 
-            // Only change the dirty state if the block isn't dirty already
-            if self.dirty_bitmap[idx] & (1 << bit) == 0 {
-                self.dirty_indexes.push(dirty_block);
-                self.dirty_bitmap[idx] |= 1 << bit;
-            }
+            // Zeroing a block overwrites all of it, so there's no need to
+            // copy it from a copy-on-write parent first
+            self.cow_blocks[dirty_idx / DBE_BITS] |=
+                1 << (dirty_idx % DBE_BITS);
+            self.memory[from..to].fill(0);
+            self.permissions[from..to].fill(Perm(0));
+        }
+        self.dirty_indexes.clear();
+        self.exec_bitmap.iter_mut().for_each(|x| *x.get_mut() = 0);
+        if let Some((_, lines)) = &mut self.cache_lines {
+            lines.clear();
         }
+        self.run_allocs.clear();
+        self.checkpoints.clear();
+        self.install_breakpoints(None);
+        self.verify_reset_idempotent();
+    }
 
-        // RaW: Set the memory to be readable
-        perms.iter_mut().for_each(|x| x.0 |= PERM_READ);
-        Some(())
+    /// Make sure a reset left no dirty state behind, such that resetting
+    /// again right away restores nothing. Only checked in debug builds.
+    #[inline(always)]
+    fn verify_reset_idempotent(&self) {
+        debug_assert!(self.dirty_indexes.is_empty(),
+                      "Dirty indexes left over after a reset");
+        debug_assert_eq!(self.dirty_popcount(), 0,
+                         "Dirty bitmap bits left over after a reset");
     }
 
-    /// Reads bytes from memory at `addr` to `buf`
-    pub fn read(&self, addr: VAddr, buf: &mut [u8]) -> Option<()> {
-        let from = addr.0;
-        let to   = addr.0.checked_add(buf.len())?;
+    /// Returns the number of bits set in the dirty bitmap, which should
+    /// always equal the number of dirty indexes
+    fn dirty_popcount(&self) -> usize {
+        self.dirty_bitmap.iter().map(|x| x.count_ones() as usize).sum()
+    }
 
-        let perms = self.permissions.get(from..to)?;
+    /// Set a software breakpoint at `addr` by replacing the byte there with
+    /// `trap`, returning the original byte.
+    ///
+    /// The trap is written regardless of permissions and isn't tracked as
+    /// dirty. Resets keep breakpoints installed, picking up the restored byte
+    /// as the new original. Breakpoints are inherited by forks.
+    pub fn set_breakpoint(&mut self, addr: VAddr, trap: u8) -> Option<u8> {
+        self.bounds(addr, 1).ok()?;
+        self.materialize(addr.0, addr.0 + 1).ok()?;
 
-        // Check that we can read from the memory
-        if perms.iter().any(|x| (x.0 & PERM_READ) == 0) {
-            return None;
-        }
+        let byte = &mut self.memory[addr.0];
+        let original = self.breakpoints.get(&addr)
+            .map_or(*byte, |&(original, _)| original);
 
-        // Read the memory
-        buf.copy_from_slice(self.memory.get(from..to)?);
+        *byte = trap;
+        self.breakpoints.insert(addr, (original, trap));
+        Some(original)
+    }
+
+    /// Remove the software breakpoint at `addr`, restoring the original byte.
+    /// Returns `None` if there's no breakpoint at `addr`.
+    pub fn clear_breakpoint(&mut self, addr: VAddr) -> Option<()> {
+        let (original, _) = self.breakpoints.remove(&addr)?;
+        self.materialize_uncharged(addr.0, addr.0 + 1);
+        self.memory[addr.0] = original;
         Some(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Temporarily restore the original bytes of all breakpoints
+    fn lift_breakpoints(&mut self) {
+        for (addr, &(original, _)) in &self.breakpoints {
+            self.memory[addr.0] = original;
+        }
+    }
 
-    const MSG: &[u8] = b"This is some text that is written into the memory.";
+    /// Reinstall all breakpoints lifted by `lift_breakpoints()`, taking the
+    /// current bytes as the originals. Bytes restored from an `other` MMU
+    /// with the same breakpoint hold its trap, so its original is used.
+    fn install_breakpoints(&mut self, other: Option<&Mmu>) {
+        let addrs: Vec<VAddr> = self.breakpoints.keys().copied().collect();
+        for addr in addrs {
+            self.materialize_uncharged(addr.0, addr.0 + 1);
+            let byte = self.memory[addr.0];
+            let original = other
+                .and_then(|other| other.breakpoints.get(&addr)
+                    .filter(|_| other.bytes(addr.0, addr.0 + 1)[0] == byte))
+                .map_or(byte, |&(original, _)| original);
 
-    #[test]
-    fn byte_perfect_allocation() {
-        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
-        mem.allocate(MSG.len()).unwrap();
+            let (saved, trap) = self.breakpoints.get_mut(&addr).unwrap();
+            *saved = original;
+            self.memory[addr.0] = *trap;
+        }
     }
 
-    #[test]
-    #[should_panic]
-    fn out_of_memory() {
-        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
-        mem.allocate(1024).unwrap(); // OK
-        mem.allocate(1024).unwrap(); // OK
-        mem.allocate(1024).unwrap(); // OK
-        mem.allocate(1024).unwrap(); // OK
-        mem.allocate(1).unwrap();    // PANIC
-    }
+    /// Restore only the permissions of the dirty blocks to those of the
+    /// `other` MMU, leaving their memory as is, and clear the dirty state.
+    ///
+    /// This is a diagnostic tool for measuring what part of `reset()` is
+    /// spent on permissions; the resulting state isn't a proper reset.
+    pub fn reset_permissions_only(&mut self, other: &Mmu) {
+        for ii in 0..self.dirty_indexes.len() {
+            let dirty_idx  = self.dirty_indexes[ii];
+            let (from, to) = self.block_range(dirty_idx);
+
+            // Reset the bitmap
+            self.dirty_bitmap[dirty_idx / DBE_BITS] &=
+                !(1 << (dirty_idx % DBE_BITS));
+
+            self.materialize_uncharged(from, to);
+            self.permissions[from..to]
+                .copy_from_slice(&other.perms(from, to));
+        }
+        self.dirty_indexes.clear();
+        self.checkpoints.clear();
+    }
+
+    /// Push a checkpoint named `name` which can later be rolled back to.
+    ///
+    /// Checkpoints cover the memory, the permissions and the allocations, but
+    /// not growing the memory. All checkpoints are dropped by forks and resets.
+    pub fn checkpoint(&mut self, name: &str) {
+        self.push_checkpoint(Some(name.to_string()));
+    }
+
+    /// Push an anonymous overlay recording the state modified until the
+    /// matching `pop_overlay()`.
+    ///
+    /// Overlays share the stack with checkpoints, so rolling back to a
+    /// checkpoint discards the overlays pushed after it.
+    pub fn push_overlay(&mut self) {
+        self.push_checkpoint(None);
+    }
+
+    /// Pop the most recent overlay, either rolling back everything modified
+    /// since it was pushed or, if `commit`ting, folding the modifications into
+    /// the enclosing overlay or checkpoint. Returns `None` if the most recent
+    /// entry of the stack isn't an overlay.
+    pub fn pop_overlay(&mut self, commit: bool) -> Option<()> {
+        if self.checkpoints.last()?.name.is_some() {
+            return None;
+        }
+        let mut overlay = self.checkpoints.pop()?;
+
+        if commit {
+            // The enclosing entry keeps the contents from before its own start
+            if let Some(parent) = self.checkpoints.last_mut() {
+                for (block, saved) in overlay.saved {
+                    parent.saved.entry(block).or_insert(saved);
+                }
+            }
+            return Some(());
+        }
+
+        self.restore_saved(&mut overlay);
+        self.alloc_base  = overlay.alloc_base;
+        self.allocations = overlay.allocations;
+        self.run_allocs  = overlay.run_allocs;
+        Some(())
+    }
+
+    /// Push a checkpoint, or an overlay if it has no `name`
+    fn push_checkpoint(&mut self, name: Option<String>) {
+        self.checkpoints.push(Checkpoint {
+            name,
+            saved:       BTreeMap::new(),
+            alloc_base:  self.alloc_base,
+            allocations: self.allocations.clone(),
+            run_allocs:  self.run_allocs.clone(),
+        });
+    }
+
+    /// Restore the blocks saved by `checkpoint` to their saved contents
+    fn restore_saved(&mut self, checkpoint: &mut Checkpoint) {
+        for (block, (memory, perms)) in std::mem::take(&mut checkpoint.saved) {
+            let (from, to) = self.block_range(block);
+            self.materialize_uncharged(from, to);
+            self.memory[from..to].copy_from_slice(&memory);
+            self.permissions[from..to].copy_from_slice(&perms);
+        }
+    }
+
+    /// Restore the state at the most recent checkpoint named `name`,
+    /// discarding all checkpoints created after it. The checkpoint itself is
+    /// kept so it can be rolled back to again.
+    ///
+    /// Returns `MmuError::InvalidArgument` if there's no such checkpoint.
+    pub fn rollback(&mut self, name: &str) -> Result<(), MmuError> {
+        let idx = self.checkpoints.iter()
+            .rposition(|cp| cp.name.as_deref() == Some(name))
+            .ok_or(MmuError::InvalidArgument)?;
+
+        // Undo the later checkpoints first, most recent first
+        let mut later: Vec<_> = self.checkpoints.drain(idx..).collect();
+        for cp in later.iter_mut().rev() {
+            self.restore_saved(cp);
+        }
+
+        let target = later.swap_remove(0);
+        self.alloc_base  = target.alloc_base;
+        self.allocations = target.allocations.clone();
+        self.run_allocs  = target.run_allocs.clone();
+        self.checkpoints.push(target);
+        Ok(())
+    }
+
+    /// Save the blocks covering `[from, to)` into the latest checkpoint, unless
+    /// they were already saved since it was created.
+    ///
+    /// The blocks must have been copied from a copy-on-write parent already.
+    fn save_for_rollback(&mut self, from: usize, to: usize) {
+        let Some(checkpoint) = self.checkpoints.last_mut() else { return };
+        if from >= to {
+            return;
+        }
+
+        for block in from / DIRTY_BLOCK_SIZE..=(to - 1) / DIRTY_BLOCK_SIZE {
+            let block_from = block * DIRTY_BLOCK_SIZE;
+            let block_to   = (block_from + DIRTY_BLOCK_SIZE)
+                .min(self.memory.len());
+            checkpoint.saved.entry(block).or_insert_with(|| {
+                (self.memory[block_from..block_to].to_vec(),
+                 self.permissions[block_from..block_to].to_vec())
+            });
+        }
+    }
+
+    /// Consume the MMU, publishing it as an immutable baseline which can be
+    /// shared between workers and forked from through `fork_shared()`
+    pub fn freeze(self) -> Arc<Mmu> {
+        Arc::new(self)
+    }
+
+    /// Fork the shared `baseline` copy-on-write, attaching it to the fork so
+    /// that `reset_self()` restores the fork to it
+    pub fn fork_shared(baseline: &Arc<Mmu>) -> Mmu {
+        let mut fork = baseline.fork_cow();
+        fork.attach_baseline(Arc::clone(baseline));
+        fork
+    }
+
+    /// Remember `baseline` as the MMU restored by `reset_self()`.
+    ///
+    /// The baseline isn't inherited by forks, whose natural baseline is the
+    /// MMU they were forked from.
+    pub fn attach_baseline(&mut self, baseline: Arc<Mmu>) {
+        self.baseline = Some(baseline);
+    }
+
+    /// Restore the memory state of the current MMU to the state of the
+    /// attached baseline. Returns `MmuError::NoBaseline` if no baseline is
+    /// attached.
+    pub fn reset_self(&mut self) -> Result<(), MmuError> {
+        let baseline = self.baseline.clone().ok_or(MmuError::NoBaseline)?;
+        self.reset(&baseline);
+        Ok(())
+    }
+
+    /// Returns the statistics accumulated over all resets
+    pub fn reset_totals(&self) -> ResetCumulative {
+        self.cumulative
+    }
+
+    /// Returns the number of `reset()`s performed, as counted by
+    /// `reset_totals()`
+    pub fn total_dirty_resets(&self) -> usize {
+        self.cumulative.resets
+    }
+
+    /// Returns the number of blocks the next reset has to restore
+    #[inline(always)]
+    pub fn dirty_block_count(&self) -> usize {
+        self.dirty_indexes.len()
+    }
+
+    /// Returns an upper bound of the number of bytes the next reset has to
+    /// restore
+    #[inline(always)]
+    pub fn dirty_byte_estimate(&self) -> usize {
+        self.dirty_indexes.len() * DIRTY_BLOCK_SIZE
+    }
+
+    /// Clear the statistics accumulated over all resets
+    pub fn clear_reset_totals(&mut self) {
+        self.cumulative = ResetCumulative::default();
+    }
+
+    /// Returns the `[from, to)` byte range covered by the dirty block `idx`,
+    /// clamped to the end of the memory
+    #[inline(always)]
+    fn block_range(&self, idx: usize) -> (usize, usize) {
+        let from = idx * DIRTY_BLOCK_SIZE;
+        let to   = ((idx + 1) * DIRTY_BLOCK_SIZE).min(self.memory.len());
+        (from.min(to), to)
+    }
+
+    /// Returns whether the block `idx` is held by the current MMU itself
+    /// rather than looked up in its copy-on-write parent
+    #[inline(always)]
+    fn is_owned(&self, idx: usize) -> bool {
+        self.cow_parent.is_none() ||
+            self.cow_blocks[idx / DBE_BITS] & (1 << (idx % DBE_BITS)) != 0
+    }
+
+    /// Returns the MMU holding the contents of the block `idx`, going up the
+    /// copy-on-write parents for as long as they don't have a copy of it
+    fn block_owner(&self, idx: usize) -> &Mmu {
+        match &self.cow_parent {
+            Some(parent) if !self.is_owned(idx) => parent.block_owner(idx),
+            _ => self,
+        }
+    }
+
+    /// Returns `field` of the `[from, to)` range, borrowed if a single MMU
+    /// holds all of it or gathered block by block from the copy-on-write
+    /// parents otherwise
+    fn gather<T: Clone>(&self, from: usize, to: usize,
+                        field: fn(&Mmu) -> &[T]) -> Cow<'_, [T]> {
+        if self.cow_parent.is_none() || from >= to {
+            return Cow::Borrowed(&field(self)[from..to]);
+        }
+
+        let blocks = from / DIRTY_BLOCK_SIZE..=(to - 1) / DIRTY_BLOCK_SIZE;
+        let owner  = self.block_owner(from / DIRTY_BLOCK_SIZE);
+        if blocks.clone().all(|x| std::ptr::eq(self.block_owner(x), owner)) {
+            return Cow::Borrowed(&field(owner)[from..to]);
+        }
+
+        let mut gathered = Vec::with_capacity(to - from);
+        for block in blocks {
+            let (block_from, block_to) = self.block_range(block);
+            gathered.extend_from_slice(&field(self.block_owner(block))
+                [block_from.max(from)..block_to.min(to)]);
+        }
+        Cow::Owned(gathered)
+    }
+
+    /// Returns the memory of the `[from, to)` range
+    fn bytes(&self, from: usize, to: usize) -> Cow<'_, [u8]> {
+        self.gather(from, to, |mmu| &mmu.memory)
+    }
+
+    /// Returns the permissions of the `[from, to)` range
+    fn perms(&self, from: usize, to: usize) -> Cow<'_, [Perm]> {
+        self.gather(from, to, |mmu| &mmu.permissions)
+    }
+
+    /// Returns the permissions of the whole memory, looking the blocks a
+    /// copy-on-write fork didn't copy yet up in its parents
+    pub fn permissions(&self) -> Cow<'_, [Perm]> {
+        self.perms(0, self.memory.len())
+    }
+
+    /// Returns the permissions at `addr`, if it's within the memory
+    fn perm_at(&self, addr: usize) -> Option<Perm> {
+        (addr < self.memory.len()).then(|| {
+            self.block_owner(addr / DIRTY_BLOCK_SIZE).permissions[addr]
+        })
+    }
+
+    /// Copy the blocks covering `[from, to)` which are still looked up in the
+    /// copy-on-write parent over to the current MMU, so they can be modified.
+    ///
+    /// The copies are charged against the fork budget. If it doesn't cover
+    /// them, nothing is copied and `MmuError::BudgetExceeded` is returned.
+    #[inline(always)]
+    fn materialize(&mut self, from: usize, to: usize) -> Result<(), MmuError> {
+        self.check_fork_budget(VAddr(from), from, to)?;
+        self.copy_from_parent(from, to, true);
+        Ok(())
+    }
+
+    /// Copy the blocks covering `[from, to)` like `materialize()` does, but
+    /// without charging them against the fork budget.
+    ///
+    /// This is for restoring a previous state, which can't fail. The blocks
+    /// touched there were already copied, save for breakpoints reinstalled
+    /// after a reset.
+    fn materialize_uncharged(&mut self, from: usize, to: usize) {
+        self.copy_from_parent(from, to, false);
+    }
+
+    /// Copy the blocks covering `[from, to)` which are still looked up in the
+    /// copy-on-write parent over to the current MMU, charging the copies
+    /// against the fork budget if `charge` is set
+    #[inline(always)]
+    fn copy_from_parent(&mut self, from: usize, to: usize, charge: bool) {
+        let Some(parent) = &self.cow_parent else { return };
+        if from >= to {
+            return;
+        }
+
+        for block in from / DIRTY_BLOCK_SIZE..=(to - 1) / DIRTY_BLOCK_SIZE {
+            let (idx, bit) = (block / DBE_BITS, block % DBE_BITS);
+            if self.cow_blocks[idx] & (1 << bit) != 0 {
+                continue;
+            }
+
+            let block_from = block * DIRTY_BLOCK_SIZE;
+            let block_to   = (block_from + DIRTY_BLOCK_SIZE)
+                .min(self.memory.len());
+            let owner = parent.block_owner(block);
+            self.memory[block_from..block_to]
+                .copy_from_slice(&owner.memory[block_from..block_to]);
+            self.permissions[block_from..block_to]
+                .copy_from_slice(&owner.permissions[block_from..block_to]);
+            self.cow_blocks[idx] |= 1 << bit;
+
+            // Charge the copy against the budget
+            if let Some(left) = self.fork_budget.as_mut().filter(|_| charge) {
+                *left -= block_to - block_from;
+                self.cow_charged[idx] |= 1 << bit;
+            }
+        }
+    }
+
+    /// Returns the number of distinct blocks written to since the last
+    /// `clear_working_set()`, accumulated across resets
+    pub fn working_set_blocks(&self) -> usize {
+        self.working_set.iter().map(|x| x.count_ones() as usize).sum()
+    }
+
+    /// Start estimating the working set anew
+    pub fn clear_working_set(&mut self) {
+        self.working_set.fill(0);
+    }
+
+    /// Additionally track dirtied memory at the granularity of `line` byte
+    /// cache lines (or stop doing so, if `None`).
+    ///
+    /// This is purely informational, resets still restore whole blocks.
+    pub fn track_cache_lines(&mut self, line: Option<usize>) {
+        assert!(line != Some(0), "Cache lines can't be empty");
+        self.cache_lines = line.map(|line| (line, BTreeSet::new()));
+    }
+
+    /// Returns the bases of the cache lines written to since the last reset,
+    /// in ascending order. Empty unless enabled by `track_cache_lines()`.
+    pub fn dirty_cache_lines(&self) -> Vec<VAddr> {
+        let Some((line, lines)) = &self.cache_lines else {
+            return Vec::new();
+        };
+        lines.iter().map(|x| VAddr(x * line)).collect()
+    }
+
+    /// Move the indexes of the dirty blocks out of the MMU, leaving no block
+    /// dirty.
+    ///
+    /// The blocks are then no longer restored by resets, it's up to the
+    /// caller to process them.
+    pub fn take_dirty_indexes(&mut self) -> Vec<usize> {
+        let dirty = std::mem::take(&mut self.dirty_indexes);
+        for &dirty_idx in &dirty {
+            self.dirty_bitmap[dirty_idx / DBE_BITS] &=
+                !(1 << (dirty_idx % DBE_BITS));
+        }
+        dirty
+    }
+
+    /// Returns whether each block of the memory is currently dirty, for
+    /// sampling write locality over time
+    pub fn sample_dirty(&self) -> Vec<bool> {
+        (0..self.memory.len().div_ceil(DIRTY_BLOCK_SIZE)).map(|block| {
+            self.dirty_bitmap[block / DBE_BITS] & (1 << (block % DBE_BITS)) != 0
+        }).collect()
+    }
+
+    /// Returns the indexes of dirty blocks whose memory or permissions
+    /// actually differ from `baseline`.
+    ///
+    /// Blocks which were written to but hold the same bytes and permissions
+    /// as the baseline are skipped.
+    pub fn truly_dirty_blocks(&self, baseline: &Mmu) -> Vec<usize> {
+        self.dirty_indexes.iter().copied().filter(|&dirty_idx| {
+            let (from, to) = self.block_range(dirty_idx);
+            self.bytes(from, to) != baseline.bytes(from, to) ||
+                self.perms(from, to) != baseline.perms(from, to)
+        }).collect()
+    }
+
+    /// Capture the memory and permissions of all dirty blocks
+    pub fn extract_dirty(&self) -> DirtyPatch {
+        let blocks = self.dirty_indexes.iter().map(|&dirty_idx| {
+            let (from, to) = self.block_range(dirty_idx);
+            (dirty_idx,
+             self.bytes(from, to).into_owned(),
+             self.perms(from, to).into_owned())
+        }).collect();
+
+        DirtyPatch { blocks }
+    }
+
+    /// Merge the blocks of `patch` onto the current MMU, marking them dirty.
+    ///
+    /// Nothing is applied if any of the blocks don't fit in memory.
+    pub fn apply_patch(&mut self, patch: &DirtyPatch) -> Result<(), MmuError> {
+        // Make sure the whole patch fits before touching anything
+        let misfit = patch.blocks.iter().find(|(dirty_idx, memory, perms)| {
+            // The indexes can be anything, so check them before using them
+            let in_memory = dirty_idx.checked_mul(DIRTY_BLOCK_SIZE)
+                .is_some_and(|from| from < self.memory.len());
+            if !in_memory {
+                return true;
+            }
+            let (from, to) = self.block_range(*dirty_idx);
+            memory.len() != to - from || perms.len() != to - from
+        });
+        if let Some((dirty_idx, memory, _)) = misfit {
+            return Err(MmuError::OutOfBounds {
+                addr: VAddr(dirty_idx.saturating_mul(DIRTY_BLOCK_SIZE)),
+                len:  memory.len(),
+            });
+        }
+
+        self.check_fork_budget_all(patch.blocks.iter().map(|(dirty_idx, ..)| {
+            let (from, to) = self.block_range(*dirty_idx);
+            (VAddr(from), from, to)
+        }))?;
+
+        for (dirty_idx, memory, perms) in &patch.blocks {
+            let (from, to) = self.block_range(*dirty_idx);
+            self.materialize(from, to)?;
+            self.save_for_rollback(from, to);
+            self.memory[from..to].copy_from_slice(memory);
+            self.permissions[from..to].copy_from_slice(perms);
+            self.mark_dirty(from, to);
+        }
+        Ok(())
+    }
+
+    /// Load the initialized `bytes` at `addr` with exactly the permissions
+    /// `perm`, regardless of whether the memory is currently writable.
+    ///
+    /// This bypasses the allocator, like `map_segment()` does.
+    pub fn load(&mut self, addr: VAddr, bytes: &[u8],
+                perm: Perm) -> Result<(), MmuError> {
+        self.map_segment(addr, bytes, bytes.len(), perm)
+    }
+
+    /// Map a `mem_size` long segment at `addr`, as an ELF loader would a
+    /// `PT_LOAD` segment.
+    ///
+    /// `file_bytes` are written to the start of the segment and the rest of
+    /// it (e.g. `.bss`) is zero-filled. The whole segment then gets `perm`.
+    pub fn map_segment(&mut self, addr: VAddr, file_bytes: &[u8],
+                       mem_size: usize, perm: Perm) -> Result<(), MmuError> {
+        let (from, to) = self.bounds(addr, mem_size)?;
+        if file_bytes.len() > mem_size {
+            return Err(MmuError::InvalidArgument);
+        }
+
+        // Nothing is written unless all of the segment can be
+        if self.touches_locked(from, to) {
+            return Err(MmuError::Locked { addr });
+        }
+        self.check_fork_budget(addr, from, to)?;
+
+        let tail = VAddr(addr.0 + file_bytes.len());
+        self.write_unchecked_perm(addr, file_bytes)?;
+        self.store(tail, mem_size - file_bytes.len(), |memory| memory.fill(0))?;
+        self.set_permissions(addr, mem_size, perm)
+    }
+
+    /// Verify that the contents of every `PT_LOAD` segment of `elf` match
+    /// the memory they were loaded into.
+    ///
+    /// Only the file-backed part of the segments is compared and permissions
+    /// are ignored.
+    pub fn verify_segments(&self, elf: &[u8]) -> Result<(), VerifyError> {
+        let segments = elf::load_segments(elf).ok_or(VerifyError::Malformed)?;
+
+        for segment in segments {
+            let file = &elf[segment.offset..segment.offset + segment.file_size];
+            let mem  = self.bounds(VAddr(segment.vaddr), segment.file_size)
+                .map(|(from, to)| self.bytes(from, to))
+                .map_err(|_| VerifyError::OutOfBounds(VAddr(segment.vaddr)))?;
+
+            // Report the first mismatching byte
+            if let Some(off) = file.iter().zip(mem.iter())
+                    .position(|(a, b)| a != b) {
+                return Err(VerifyError::Mismatch(VAddr(segment.vaddr + off)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the runs of identical, non-zero permissions as
+    /// `(base, size, perm)`
+    fn permission_regions(&self) -> Vec<(VAddr, usize, Perm)> {
+        let mut regions: Vec<(VAddr, usize, Perm)> = Vec::new();
+        for (addr, &perm) in self.permissions().iter().enumerate() {
+            match regions.last_mut() {
+                Some((base, size, last)) if *last == perm &&
+                        base.0 + *size == addr => *size += 1,
+                _ if perm.0 != 0 => regions.push((VAddr(addr), 1, perm)),
+                _ => {},
+            }
+        }
+        regions
+    }
+
+    /// Returns the number of runs of identical, non-zero permissions, i.e.
+    /// how fragmented the permission map is
+    pub fn permission_region_count(&self) -> usize {
+        self.permission_regions().len()
+    }
+
+    /// Returns the number of distinct non-zero permissions in the memory
+    pub fn distinct_permissions(&self) -> usize {
+        self.permission_regions().into_iter().map(|(_, _, perm)| perm)
+            .collect::<BTreeSet<_>>().len()
+    }
+
+    /// Returns the runs of permissions which differ from the ones in
+    /// `baseline` as `(base, size, perm)`, `perm` being the current
+    /// permission of the whole run.
+    ///
+    /// Memory past the end of `baseline` is compared against `Perm(0)`.
+    /// Together with `extract_dirty()` this is everything needed to bring a
+    /// fork of `baseline` to the state of this MMU.
+    pub fn permission_delta(&self, baseline: &Mmu)
+            -> Vec<(VAddr, usize, Perm)> {
+        let mut delta: Vec<(VAddr, usize, Perm)> = Vec::new();
+        let old_perms = baseline.permissions();
+        for (addr, &perm) in self.permissions().iter().enumerate() {
+            let old = old_perms.get(addr).copied()
+                .unwrap_or(Perm(0));
+            match delta.last_mut() {
+                _ if old == perm => {},
+                Some((base, size, last)) if *last == perm &&
+                        base.0 + *size == addr => *size += 1,
+                _ => delta.push((VAddr(addr), 1, perm)),
+            }
+        }
+        delta
+    }
+
+    /// Capture the current permissions of the whole memory, to be compared
+    /// against later by `permissions_changed_since()`
+    pub fn snapshot_permissions(&self) -> PermSnapshot {
+        PermSnapshot { perms: self.permissions().into_owned() }
+    }
+
+    /// Returns the runs of permissions which changed since `snap` was taken
+    /// as `(base, size, old, new)`.
+    ///
+    /// Memory grown since the snapshot is compared against `Perm(0)`.
+    pub fn permissions_changed_since(&self, snap: &PermSnapshot)
+            -> Vec<(VAddr, usize, Perm, Perm)> {
+        let mut changed: Vec<(VAddr, usize, Perm, Perm)> = Vec::new();
+        for (addr, &new) in self.permissions().iter().enumerate() {
+            let old = snap.perms.get(addr).copied().unwrap_or(Perm(0));
+            match changed.last_mut() {
+                _ if old == new => {},
+                Some((base, size, last_old, last_new)) if *last_old == old &&
+                        *last_new == new && base.0 + *size == addr =>
+                    *size += 1,
+                _ => changed.push((VAddr(addr), 1, old, new)),
+            }
+        }
+        changed
+    }
+
+    /// Check that every `(base, size, perm)` region of `expected` has
+    /// exactly the permissions `perm`, reporting the first one which doesn't
+    pub fn assert_layout(&self, expected: &[(VAddr, usize, Perm)])
+            -> Result<(), LayoutMismatch> {
+        let perms = self.permissions();
+        for &(base, size, perm) in expected {
+            let actual = |addr: usize| perms.get(addr).copied()
+                .unwrap_or(Perm(0));
+            let end = base.0.saturating_add(size);
+            if let Some(addr) = (base.0..end).find(|&x| actual(x) != perm) {
+                return Err(LayoutMismatch {
+                    range:    (base, size),
+                    addr:     VAddr(addr),
+                    expected: perm,
+                    actual:   actual(addr),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the permission layout against `policy`, returning the
+    /// `(base, size)` of every region violating it
+    pub fn assert_wx_policy(&self, policy: WxPolicy)
+            -> Result<(), Vec<(VAddr, usize)>> {
+        let violates = |perm: Perm| match policy {
+            WxPolicy::Exclusive => (perm.0 & (PERM_WRITE | PERM_EXEC)) ==
+                (PERM_WRITE | PERM_EXEC),
+            WxPolicy::NoExec    => (perm.0 & PERM_EXEC) != 0,
+        };
+
+        // Adjacent offending regions are reported as one
+        let mut offending: Vec<(VAddr, usize)> = Vec::new();
+        for (base, size, _) in self.permission_regions().into_iter()
+                .filter(|&(_, _, perm)| violates(perm)) {
+            match offending.last_mut() {
+                Some((last, len)) if last.0 + *len == base.0 => *len += size,
+                _ => offending.push((base, size)),
+            }
+        }
+
+        if offending.is_empty() {
+            Ok(())
+        } else {
+            Err(offending)
+        }
+    }
+
+    /// Name the `len` long region starting at `addr` (e.g. `heap`, `.text`)
+    /// for `render_map()`. Labels are kept across forks and resets.
+    pub fn label_region(&mut self, addr: VAddr, len: usize, name: &str) {
+        self.labels.insert(addr, (len, name.to_string()));
+    }
+
+    /// Render the mapped regions as one `start-end rwx labels` line each,
+    /// listing the labels of all labeled regions overlapping them
+    pub fn render_map(&self) -> String {
+        let mut map = String::new();
+        for (base, size, perm) in self.permission_regions() {
+            let end  = base.0 + size;
+            let flag = |bit, c| if (perm.0 & bit) != 0 { c } else { '-' };
+            let names: Vec<&str> = self.labels.iter()
+                .filter(|(addr, (len, _))| {
+                    addr.0 < end && addr.0 + len > base.0
+                })
+                .map(|(_, (_, name))| name.as_str())
+                .collect();
+
+            map.push_str(&format!("{:#010x}-{:#010x} {}{}{} {}\n",
+                base.0, end, flag(PERM_READ, 'r'), flag(PERM_WRITE, 'w'),
+                flag(PERM_EXEC, 'x'), names.join(",")));
+        }
+        map
+    }
+
+    /// Write the mapped regions of the memory to `w` as a minidump with a
+    /// `MemoryInfoListStream` holding their permissions and a
+    /// `Memory64ListStream` holding their contents
+    pub fn write_minidump(&self, w: &mut dyn Write) -> io::Result<()> {
+        let regions = self.permission_regions();
+
+        // The streams follow the header and the two entry stream directory
+        let info_rva  = MINIDUMP_HEADER_SIZE + 2 * 12;
+        let info_size = 16 + regions.len() * MEMORY_INFO_SIZE;
+        let mem_rva   = info_rva + info_size;
+        let mem_size  = 16 + regions.len() * 16;
+        let data_rva  = mem_rva + mem_size;
+        let rva = |x: usize| u32::try_from(x).map_err(|_|
+            io::Error::new(io::ErrorKind::InvalidInput, "Minidump too large"));
+
+        let mut out = Vec::with_capacity(data_rva);
+        let u32 = |out: &mut Vec<u8>, x: u32|
+            out.extend_from_slice(&x.to_le_bytes());
+        let u64 = |out: &mut Vec<u8>, x: u64|
+            out.extend_from_slice(&x.to_le_bytes());
+
+        // Header
+        u32(&mut out, MINIDUMP_SIGNATURE);
+        u32(&mut out, MINIDUMP_VERSION);
+        u32(&mut out, 2);
+        u32(&mut out, MINIDUMP_HEADER_SIZE as u32);
+        u32(&mut out, 0);
+        u32(&mut out, 0);
+        u64(&mut out, 0);
+
+        // Stream directory
+        u32(&mut out, MEMORY_INFO_LIST_STREAM);
+        u32(&mut out, rva(info_size)?);
+        u32(&mut out, rva(info_rva)?);
+        u32(&mut out, MEMORY64_LIST_STREAM);
+        u32(&mut out, rva(mem_size)?);
+        u32(&mut out, rva(mem_rva)?);
+
+        // Memory info list
+        u32(&mut out, 16);
+        u32(&mut out, MEMORY_INFO_SIZE as u32);
+        u64(&mut out, regions.len() as u64);
+        for &(base, size, perm) in &regions {
+            u64(&mut out, base.0 as u64);
+            u64(&mut out, base.0 as u64);
+            u32(&mut out, perm_to_protect(perm));
+            u32(&mut out, 0);
+            u64(&mut out, size as u64);
+            u32(&mut out, MEM_COMMIT);
+            u32(&mut out, perm_to_protect(perm));
+            u32(&mut out, MEM_PRIVATE);
+            u32(&mut out, 0);
+        }
+
+        // Memory64 list, with the contents of the regions following it
+        u64(&mut out, regions.len() as u64);
+        u64(&mut out, data_rva as u64);
+        for &(base, size, _) in &regions {
+            u64(&mut out, base.0 as u64);
+            u64(&mut out, size as u64);
+        }
+
+        w.write_all(&out)?;
+        for &(base, size, _) in &regions {
+            w.write_all(&self.bytes(base.0, base.0 + size))?;
+        }
+        Ok(())
+    }
+
+    /// Read a minidump written by `write_minidump()` (or any other minidump
+    /// with a `Memory64ListStream`) into a new MMU just large enough to hold
+    /// all of its memory ranges.
+    ///
+    /// Ranges without a matching `MemoryInfoListStream` entry are mapped as
+    /// read-write. The allocation base is placed after the last range. Dumps
+    /// with ranges above 4 GiB are rejected, as the MMU would have to be that
+    /// large.
+    pub fn read_minidump(r: &mut dyn Read) -> io::Result<Mmu> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        let mut dump = Vec::new();
+        r.read_to_end(&mut dump)?;
+        if minidump_u32(&dump, 0)? != MINIDUMP_SIGNATURE {
+            return Err(invalid("Not a minidump"));
+        }
+
+        // Find the streams we care about
+        let mut info_rva = None;
+        let mut mem_rva  = None;
+        let streams = minidump_u32(&dump, 8)? as usize;
+        let dir_rva = minidump_u32(&dump, 12)? as usize;
+        minidump_table(&dump, dir_rva, streams, 12)?;
+        for ii in 0..streams {
+            let entry = dir_rva + ii * 12;
+            let rva = minidump_u32(&dump, entry + 8)? as usize;
+            match minidump_u32(&dump, entry)? {
+                MEMORY_INFO_LIST_STREAM => info_rva = Some(rva),
+                MEMORY64_LIST_STREAM    => mem_rva  = Some(rva),
+                _ => {},
+            }
+        }
+        let mem_rva = mem_rva.ok_or_else(|| invalid("No memory list"))?;
+
+        // Collect the permissions of the regions
+        let mut protections = BTreeMap::new();
+        if let Some(info_rva) = info_rva {
+            let header_size = minidump_u32(&dump, info_rva)? as usize;
+            let entry_size  = minidump_u32(&dump, info_rva + 4)? as usize;
+            let entries     = minidump_u64(&dump, info_rva + 8)? as usize;
+            if entry_size < MEMORY_INFO_SIZE {
+                return Err(invalid("Memory info entries too small"));
+            }
+
+            let table = info_rva.checked_add(header_size)
+                .ok_or_else(|| invalid("Memory info list overflows"))?;
+            minidump_table(&dump, table, entries, entry_size)?;
+            for ii in 0..entries {
+                let entry = table + ii * entry_size;
+                protections.insert(minidump_u64(&dump, entry)? as usize,
+                                   minidump_u32(&dump, entry + 36)?);
+            }
+        }
+
+        // Collect the memory ranges and their contents
+        let count    = minidump_u64(&dump, mem_rva)? as usize;
+        let mut data = minidump_u64(&dump, mem_rva + 8)? as usize;
+        minidump_table(&dump, mem_rva + 16, count, 16)?;
+        let mut ranges = Vec::new();
+        for ii in 0..count {
+            let base = minidump_u64(&dump, mem_rva + 16 + ii * 16)? as usize;
+            let size = minidump_u64(&dump, mem_rva + 24 + ii * 16)? as usize;
+            let bytes = data.checked_add(size)
+                .and_then(|end| dump.get(data..end))
+                .ok_or_else(|| invalid("Memory range outside of the dump"))?;
+            base.checked_add(size).filter(|&end| end <= MINIDUMP_MAX_END)
+                .ok_or_else(|| invalid("Memory range too high up"))?;
+
+            ranges.push((base, bytes));
+            data += size;
+        }
+
+        let end = ranges.iter().map(|(base, bytes)| base + bytes.len())
+            .max().unwrap_or(0);
+        let mut mmu = Mmu::new(end.max(DIRTY_BLOCK_SIZE));
+        for (base, bytes) in ranges {
+            let perm = protections.get(&base).copied()
+                .map_or(Perm(PERM_READ | PERM_WRITE), protect_to_perm);
+            mmu.memory[base..base + bytes.len()].copy_from_slice(bytes);
+            mmu.apply_permissions(VAddr(base), bytes.len(), perm)
+                .expect("Ranges are within the memory");
+        }
+        mmu.alloc_base = end.checked_add(ALIGNMENT)
+            .map(|end| VAddr(end & !ALIGNMENT))
+            .ok_or_else(|| invalid("Memory range too high up"))?;
+        Ok(mmu)
+    }
+
+    /// Serialize the memory, the permissions and the allocation base into a
+    /// snapshot which can be loaded back by `deserialize()`.
+    ///
+    /// The rest of the state (allocation metadata, labels, dirty tracking,
+    /// ...) isn't part of the snapshot.
+    pub fn serialize(&self) -> Vec<u8> {
+        let len = self.memory.len();
+        let mut out = Vec::with_capacity(SNAPSHOT_HEADER_SIZE + len * 2);
+        out.extend_from_slice(&SNAPSHOT_MAGIC.to_le_bytes());
+        out.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        out.extend_from_slice(&(len as u64).to_le_bytes());
+        out.extend_from_slice(&(self.alloc_base.0 as u64).to_le_bytes());
+        out.extend_from_slice(&self.bytes(0, len));
+        out.extend(self.permissions().iter().map(|perm| perm.0));
+        out
+    }
+
+    /// Load a snapshot written by `serialize()` into a new, clean MMU, as if
+    /// it was forked from the serialized one.
+    ///
+    /// Returns `None` if `bytes` aren't a snapshot of the current version.
+    pub fn deserialize(bytes: &[u8]) -> Option<Mmu> {
+        let field = |offset: usize| -> Option<u64> {
+            let bytes = bytes.get(offset..offset + 8)?;
+            Some(u64::from_le_bytes(bytes.try_into().ok()?))
+        };
+        let header = field(0)?;
+        if header as u32 != SNAPSHOT_MAGIC ||
+                (header >> 32) as u32 != SNAPSHOT_VERSION {
+            return None;
+        }
+
+        // The memory and the permissions follow the header
+        let len        = usize::try_from(field(8)?).ok()?;
+        let alloc_base = usize::try_from(field(16)?).ok()?;
+        let data       = &bytes[SNAPSHOT_HEADER_SIZE..];
+        if len < DIRTY_BLOCK_SIZE || len % (ALIGNMENT + 1) != 0 ||
+                alloc_base > len ||
+                len.checked_mul(2)? != data.len() {
+            return None;
+        }
+
+        let mut mmu = Mmu::new(len);
+        let (memory, perms) = data.split_at(len);
+        mmu.memory.copy_from_slice(memory);
+        mmu.permissions.iter_mut().zip(perms)
+            .for_each(|(perm, &x)| *perm = Perm(x));
+        mmu.alloc_base = VAddr(alloc_base);
+        Some(mmu)
+    }
+
+    /// Allocate a region in memory
+    pub fn allocate(&mut self, size: usize) -> Result<VAddr, MmuError> {
+        self.allocate_aligned(size, ALIGNMENT + 1)
+    }
+
+    /// Allocate a region in memory whose base is aligned to `align`, which
+    /// must be a power of two.
+    ///
+    /// The padding skipped to align the base is left without any permissions.
+    pub fn allocate_aligned(&mut self, size: usize,
+                            align: usize) -> Result<VAddr, MmuError> {
+        self.allocate_aligned_perm(size, align, self.alloc_perm)
+    }
+
+    /// Set the permissions `allocate` gives to fresh allocations (writable and
+    /// readable only once written, to catch uninitialized reads, by default)
+    pub fn set_default_alloc_perm(&mut self, perm: Perm) {
+        self.alloc_perm = perm;
+    }
+
+    /// Leave an unmapped gap of `size` bytes after every future allocation,
+    /// so that linear overflows out of a region fault instead of running into
+    /// the next one. There are no gaps by default.
+    pub fn set_alloc_guard(&mut self, size: usize) {
+        self.alloc_guard = size;
+    }
+
+    /// Allocate `size` bytes of memory with the permissions `perm`
+    pub fn allocate_perm(&mut self, size: usize, perm: Perm)
+            -> Result<VAddr, MmuError> {
+        self.allocate_aligned_perm(size, ALIGNMENT + 1, perm)
+    }
+
+    /// Allocate `size` bytes of memory aligned to `align_to` with the
+    /// permissions `perm`
+    fn allocate_aligned_perm(&mut self, size: usize, align_to: usize,
+                             perm: Perm) -> Result<VAddr, MmuError> {
+        if !align_to.is_power_of_two() {
+            return Err(MmuError::InvalidArgument);
+        }
+
+        // Align the base of the allocation
+        let pad_base = self.alloc_base;
+        let cur_base = VAddr(pad_base.0.checked_add(align_to - 1)
+            .ok_or(MmuError::Overflow)? & !(align_to - 1));
+
+        // Update the allocation base, leaving the guard gap behind
+        let next_base = VAddr(size.checked_add(self.alloc_guard)
+            .and_then(|x| cur_base.0.checked_add(align(x)))
+            .ok_or(MmuError::Overflow)?);
+
+        // Don't allocate OOM
+        if next_base.0 > self.memory.len() {
+            return Err(MmuError::OutOfMemory);
+        }
+
+        // Set the permissions of the memory and clear those of the padding
+        // and the gap
+        self.apply_permissions(pad_base, cur_base.0 - pad_base.0, Perm(0))?;
+        self.set_permissions(cur_base, size, perm)?;
+        self.apply_permissions(VAddr(cur_base.0 + size),
+                               next_base.0 - cur_base.0 - size, Perm(0))?;
+
+        self.alloc_base = next_base;
+        self.allocations.insert(cur_base, Allocation {
+            size,
+            children: Vec::new(),
+            reserved: (pad_base, next_base),
+        });
+        self.run_allocs.insert(cur_base, size);
+        Ok(cur_base)
+    }
+
+    /// Free the `size` long region at `addr`, stripping all of its
+    /// permissions so that any later access to it faults as a use-after-free.
+    ///
+    /// The region must lie within a single live allocation. Freeing exactly
+    /// one of its sub-allocations drops that from the parent. Freeing the
+    /// whole allocation also drops it (and its sub-allocations) from the
+    /// allocation metadata, so freeing it again fails. The address space
+    /// isn't reclaimed for reuse.
+    ///
+    /// Returns the `(base, size)` of the sub-allocations which were still
+    /// live when their parent got freed, so they're invalidated along with it.
+    pub fn free(&mut self, addr: VAddr,
+                size: usize) -> Result<Vec<(VAddr, usize)>, MmuError> {
+        let end = addr.0.checked_add(size).ok_or(MmuError::Overflow)?;
+        let (&base, alloc) = self.allocations.range(..=addr).next_back()
+            .filter(|(base, alloc)| end <= base.0 + alloc.size)
+            .ok_or(MmuError::NotAllocated { addr })?;
+        let whole = base == addr && size == alloc.size;
+
+        // Resets have to restore the permissions
+        self.apply_permissions(addr, size, Perm(0))?;
+        self.mark_dirty(addr.0, end);
+
+        if !whole {
+            if let Some(alloc) = self.allocations.get_mut(&base) {
+                alloc.children.retain(|&child| child != (addr, size));
+            }
+            return Ok(Vec::new());
+        }
+        self.run_allocs.remove(&addr);
+        Ok(self.allocations.remove(&addr).map(|alloc| alloc.children)
+            .unwrap_or_default())
+    }
+
+    /// Run `f` on the MMU, reclaiming everything it allocated once it
+    /// returns.
+    ///
+    /// The allocation base is restored to where it was before `f`, and the
+    /// space allocated within is stripped of all of its permissions so that
+    /// any later access to it faults until it's allocated again.
+    pub fn alloc_scope<R>(&mut self, f: impl FnOnce(&mut Mmu) -> R) -> R {
+        let base = self.alloc_base;
+        let ret = f(self);
+
+        if self.alloc_base > base {
+            // Resets have to restore the permissions
+            let size = self.alloc_base.0 - base.0;
+            if self.apply_permissions(base, size, Perm(0)).is_ok() {
+                self.mark_dirty(base.0, base.0 + size);
+            }
+            self.allocations.split_off(&base);
+            self.run_allocs.split_off(&base);
+            self.alloc_base = base;
+        }
+        ret
+    }
+
+    /// Allocate a `size` byte ring buffer, accessed through `ring_read()` and
+    /// `ring_write()`
+    pub fn map_ring(&mut self, size: usize) -> Result<Ring, MmuError> {
+        if size == 0 {
+            return Err(MmuError::InvalidArgument);
+        }
+        Ok(Ring { base: self.allocate(size)?, size })
+    }
+
+    /// Write `buf` into `ring` starting at `offset`, wrapping around the end
+    /// of the ring (possibly multiple times).
+    ///
+    /// Nothing is written unless all of the touched memory is writable.
+    pub fn ring_write(&mut self, ring: &Ring, offset: usize,
+                      buf: &[u8]) -> Result<(), MmuError> {
+        for (addr, _, len) in ring.pieces(offset, buf.len()) {
+            self.ensure_writable(addr, len)?;
+        }
+        for (addr, off, len) in ring.pieces(offset, buf.len()) {
+            self.write_unchecked_perm(addr, &buf[off..off + len])?;
+        }
+        Ok(())
+    }
+
+    /// Read `buf` from `ring` starting at `offset`, wrapping around the end
+    /// of the ring (possibly multiple times)
+    pub fn ring_read(&self, ring: &Ring, offset: usize,
+                     buf: &mut [u8]) -> Result<(), MmuError> {
+        let len = buf.len();
+        for (addr, off, len) in ring.pieces(offset, len) {
+            self.read(addr, &mut buf[off..off + len])?;
+        }
+        Ok(())
+    }
+
+    /// Resize the `old_len` long allocation at `addr` to `new_len` bytes,
+    /// returning its (possibly new) base.
+    ///
+    /// Shrinking strips the permissions of the tail. Growing happens in place
+    /// if no other allocation follows closely enough; the new bytes get the
+    /// same permissions as a fresh allocation. Otherwise, if `may_move`, the
+    /// allocation is moved to a fresh one with its contents and permissions
+    /// and the old one is unmapped.
+    pub fn mremap(&mut self, addr: VAddr, old_len: usize, new_len: usize,
+                  may_move: bool) -> Result<VAddr, MmuError> {
+        if self.allocations.get(&addr).map(|x| x.size) != Some(old_len) {
+            return Err(MmuError::NotAllocated { addr });
+        }
+
+        // Shrinking unmaps the tail, which resets have to map again
+        if new_len <= old_len {
+            self.apply_permissions(VAddr(addr.0 + new_len), old_len - new_len,
+                                   Perm(0))?;
+            self.mark_dirty(addr.0 + new_len, addr.0 + old_len);
+            self.resize_allocation(addr, new_len,
+                                   VAddr(addr.0 + align(new_len +
+                                                        self.alloc_guard)));
+            return Ok(addr);
+        }
+
+        // Grow in place if nothing (including the guard gap) lies in the way
+        let new_end = new_len.checked_add(self.alloc_guard)
+            .and_then(|x| addr.0.checked_add(align(x)))
+            .ok_or(MmuError::Overflow)?;
+        let limit = self.allocations.range(VAddr(addr.0 + 1)..).next()
+            .map_or(self.memory.len(), |(next, _)| next.0);
+        if new_end <= limit {
+            self.set_permissions(VAddr(addr.0 + old_len), new_len - old_len,
+                                 self.alloc_perm)?;
+            self.alloc_base = VAddr(self.alloc_base.0.max(new_end));
+            self.resize_allocation(addr, new_len, VAddr(new_end));
+            return Ok(addr);
+        }
+
+        if !may_move {
+            return Err(MmuError::OutOfMemory);
+        }
+
+        // Move the contents and permissions over to a fresh allocation
+        self.materialize(addr.0, addr.0 + old_len)?;
+        let new = self.allocate(new_len)?;
+        self.materialize(new.0, new.0 + old_len)?;
+        self.save_for_rollback(new.0, new.0 + old_len);
+        self.memory.copy_within(addr.0..addr.0 + old_len, new.0);
+        self.permissions.copy_within(addr.0..addr.0 + old_len, new.0);
+        self.mark_dirty(new.0, new.0 + old_len);
+
+        // Resets have to map the old allocation again
+        self.apply_permissions(addr, old_len, Perm(0))?;
+        self.mark_dirty(addr.0, addr.0 + old_len);
+        self.allocations.remove(&addr);
+        self.run_allocs.remove(&addr);
+        Ok(new)
+    }
+
+    /// Update the size of the allocation at `addr` and the end of the memory
+    /// reserved for it in the metadata
+    fn resize_allocation(&mut self, addr: VAddr, size: usize, end: VAddr) {
+        if let Some(alloc) = self.allocations.get_mut(&addr) {
+            alloc.size       = size;
+            alloc.reserved.1 = end;
+        }
+        if let Some(run_size) = self.run_allocs.get_mut(&addr) {
+            *run_size = size;
+        }
+    }
+
+    /// Carve a `size` long sub-allocation at `offset` out of the `parent`
+    /// allocation.
+    ///
+    /// The sub-range must lie entirely within the parent allocation. The
+    /// relationship is recorded so the children of a parent can be queried.
+    pub fn suballocate(&mut self, parent: VAddr, offset: usize,
+                       size: usize) -> Result<VAddr, MmuError> {
+        let alloc = self.allocations.get_mut(&parent)
+            .ok_or(MmuError::NotAllocated { addr: parent })?;
+
+        // Make sure the sub-range lies within the parent
+        let base = VAddr(parent.0.checked_add(offset)
+            .ok_or(MmuError::Overflow)?);
+        if offset.checked_add(size).ok_or(MmuError::Overflow)? > alloc.size {
+            return Err(MmuError::OutOfBounds { addr: base, len: size });
+        }
+
+        alloc.children.push((base, size));
+        Ok(base)
+    }
+
+    /// Returns the `(base, size)` of all allocations made since the last fork
+    /// or reset which haven't been freed
+    pub fn check_leaks(&self) -> Vec<(VAddr, usize)> {
+        self.run_allocs.iter().map(|(&base, &size)| (base, size))
+            .collect()
+    }
+
+    /// Returns the number of bytes left for the bump allocator
+    pub fn free_space(&self) -> usize {
+        self.memory.len() - self.alloc_base.0
+    }
+
+    /// Returns the size of the largest contiguous run of memory which isn't
+    /// reserved by any live allocation, including its alignment padding and
+    /// guard gap. The null guard is never free.
+    ///
+    /// As long as nothing is ever freed, this equals `free_space()`.
+    pub fn largest_free(&self) -> usize {
+        let mut largest = 0;
+        let mut cursor  = align(self.null_guard);
+        for alloc in self.allocations.values() {
+            let (from, to) = alloc.reserved;
+            largest = largest.max(from.0.saturating_sub(cursor));
+            cursor  = cursor.max(to.0);
+        }
+
+        // Whatever follows the last allocation runs into the bump space
+        largest.max(self.memory.len().saturating_sub(cursor))
+    }
+
+    /// Returns the sum of the requested sizes of all live allocations
+    pub fn total_allocated(&self) -> usize {
+        self.allocations.values().map(|alloc| alloc.size).sum()
+    }
+
+    /// Returns the number of bytes with any permission set
+    pub fn total_mapped(&self) -> usize {
+        self.permissions().iter().filter(|x| x.0 != 0).count()
+    }
+
+    /// Returns the live sub-allocations of `parent` as `(base, size)`
+    pub fn children(&self, parent: VAddr) -> Option<&[(VAddr, usize)]> {
+        Some(&self.allocations.get(&parent)?.children)
+    }
+
+    /// Lay out the memory of a new thread: a guard page, a `stack` long stack
+    /// growing down towards the guard and a zeroed `tls` long TLS block.
+    ///
+    /// The guard page has no permissions so stack overflows trap.
+    pub fn setup_thread(&mut self, stack: usize,
+                        tls: usize) -> Result<ThreadLayout, MmuError> {
+        // Allocate the guard below the stack without any permissions
+        let guard = self.allocate_perm(THREAD_GUARD_SIZE, Perm(0))?;
+
+        // The stack grows down, so the stack pointer starts at its top. Its
+        // permissions don't depend on `set_default_alloc_perm()`
+        let rw = Perm(PERM_RAW | PERM_WRITE);
+        let stack_base = self.allocate_perm(stack, rw)?;
+        let sp = VAddr((stack_base.0 + stack) & !ALIGNMENT);
+
+        // Zero out the TLS, which also makes it readable
+        let tls_base = self.allocate_perm(tls, rw)?;
+        self.fill(tls_base, 0, tls)?;
+
+        Ok(ThreadLayout { sp, tls_base, guard })
+    }
+
+    /// Set the permissions of a `size` long memory block starting from `addr`
+    /// to `perm`.
+    ///
+    /// If executable memory is denied by default, `PERM_EXEC` is stripped from
+    /// `perm` and has to be granted through `finalize_code()` instead.
+    pub fn set_permissions(&mut self, addr: VAddr,
+                           size: usize, perm: Perm) -> Result<(), MmuError> {
+        self.modify_permissions(addr, size, perm, PermOp::Replace)
+    }
+
+    /// Combine the permissions of a `size` long memory block starting from
+    /// `addr` with `perm` as specified by `op`.
+    ///
+    /// Like `set_permissions()`, `PERM_EXEC` can't be granted this way if
+    /// executable memory is denied by default. It can always be removed.
+    pub fn modify_permissions(&mut self, addr: VAddr, size: usize, perm: Perm,
+                              op: PermOp) -> Result<(), MmuError> {
+        let perm = if self.deny_exec && op != PermOp::Remove {
+            Perm(perm.0 & !PERM_EXEC)
+        } else {
+            perm
+        };
+        self.update_permissions(addr, size, |old| match op {
+            PermOp::Replace => perm,
+            PermOp::Add     => Perm(old.0 | perm.0),
+            PermOp::Remove  => Perm(old.0 & !perm.0),
+        })
+    }
+
+    /// Mark a `size` long memory block starting from `addr` as code, making it
+    /// readable and executable but no longer writable.
+    ///
+    /// This is the only way to gain `PERM_EXEC` while executable memory is
+    /// denied by default.
+    pub fn finalize_code(&mut self, addr: VAddr,
+                         size: usize) -> Result<(), MmuError> {
+        self.apply_permissions(addr, size, Perm(PERM_READ | PERM_EXEC))
+    }
+
+    /// Make the `len` long region at `addr` immutable for the rest of the
+    /// run by clearing its `PERM_WRITE`.
+    ///
+    /// The blocks fully covered by the region are additionally locked: they
+    /// are never tracked as dirty again, so resets leave them as they are now
+    /// instead of restoring them, and not even `write_unchecked_perm()` can
+    /// write to them. Locks are inherited by forks.
+    pub fn lock_region(&mut self, addr: VAddr,
+                       len: usize) -> Result<(), MmuError> {
+        let (from, to) = self.bounds(addr, len)?;
+        self.materialize(from, to)?;
+        self.permissions[from..to].iter_mut().for_each(|x| x.0 &= !PERM_WRITE);
+
+        for block in from.div_ceil(DIRTY_BLOCK_SIZE)..to / DIRTY_BLOCK_SIZE {
+            let (idx, bit) = (block / DBE_BITS, block % DBE_BITS);
+            self.locked_blocks[idx] |= 1 << bit;
+
+            // Whatever the block holds now is what it keeps holding
+            if self.dirty_bitmap[idx] & (1 << bit) != 0 {
+                self.dirty_bitmap[idx] &= !(1 << bit);
+                self.dirty_indexes.retain(|&x| x != block);
+            }
+        }
+        Ok(())
+    }
+
+    /// Make the blocks overlapping the `len` long region at `addr` persistent
+    /// (or volatile again).
+    ///
+    /// Persistent blocks stay writable and are still tracked as dirty, but
+    /// resets leave them as they are instead of restoring them. Whole blocks
+    /// are affected, even if the region only covers parts of them.
+    pub fn set_persistent(&mut self, addr: VAddr, len: usize,
+                          persistent: bool) -> Result<(), MmuError> {
+        let (_, to) = self.bounds(addr, len)?;
+
+        for block in addr.0 / DIRTY_BLOCK_SIZE..to.div_ceil(DIRTY_BLOCK_SIZE) {
+            let (idx, bit) = (block / DBE_BITS, block % DBE_BITS);
+            if persistent {
+                self.persistent[idx] |= 1 << bit;
+            } else {
+                self.persistent[idx] &= !(1 << bit);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop tracking the persistent blocks as dirty, so that resets skip them
+    fn keep_persistent(&mut self) {
+        let mut ii = 0;
+        while ii < self.dirty_indexes.len() {
+            let block = self.dirty_indexes[ii];
+            if self.is_persistent(block) {
+                self.dirty_bitmap[block / DBE_BITS] &=
+                    !(1 << (block % DBE_BITS));
+                self.dirty_indexes.swap_remove(ii);
+            } else {
+                ii += 1;
+            }
+        }
+    }
+
+    /// Returns whether the dirty block `idx` is made persistent by
+    /// `set_persistent()`
+    #[inline(always)]
+    fn is_persistent(&self, idx: usize) -> bool {
+        self.persistent[idx / DBE_BITS] & (1 << (idx % DBE_BITS)) != 0
+    }
+
+    /// Returns whether the dirty block `idx` is locked by `lock_region()`
+    #[inline(always)]
+    fn is_locked(&self, idx: usize) -> bool {
+        self.locked_blocks[idx / DBE_BITS] & (1 << (idx % DBE_BITS)) != 0
+    }
+
+    /// Returns whether any block overlapping `[from, to)` is locked
+    fn touches_locked(&self, from: usize, to: usize) -> bool {
+        from < to && (from / DIRTY_BLOCK_SIZE..=(to - 1) / DIRTY_BLOCK_SIZE)
+            .any(|block| self.is_locked(block))
+    }
+
+    /// Deny (or allow) `PERM_EXEC` from being set through the regular
+    /// permission paths, modeling NX by default
+    pub fn set_deny_exec(&mut self, deny: bool) {
+        self.deny_exec = deny;
+    }
+
+    /// Enforce W^X (or stop doing so) by clearing `PERM_EXEC` from all bytes
+    /// written to, so that modified code has to be finalized again before it
+    /// can be fetched
+    pub fn set_strict_wx(&mut self, strict: bool) {
+        self.strict_wx = strict;
+    }
+
+    /// Permanently unmap the first `size` bytes of the memory (e.g.
+    /// `NULL_GUARD_SIZE`), so that any access near NULL faults. Allocations
+    /// are placed above the guard from now on.
+    ///
+    /// Permissions given to the guard are silently dropped. There's no guard
+    /// by default.
+    pub fn set_null_guard(&mut self, size: usize) -> Result<(), MmuError> {
+        self.bounds(VAddr(0), size)?;
+        self.null_guard = 0;
+        self.apply_permissions(VAddr(0), size, Perm(0))?;
+
+        self.null_guard = size;
+        self.alloc_base = VAddr(self.alloc_base.0.max(align(size)));
+        Ok(())
+    }
+
+    /// Set the permissions of a `size` long memory block starting from `addr`
+    /// to exactly `perm`
+    fn apply_permissions(&mut self, addr: VAddr,
+                         size: usize, perm: Perm) -> Result<(), MmuError> {
+        self.update_permissions(addr, size, |_| perm)
+    }
+
+    /// Replace the permissions of a `size` long memory block starting from
+    /// `addr` with what `update` makes of them
+    #[inline(always)]
+    fn update_permissions(&mut self, addr: VAddr, size: usize,
+                          update: impl Fn(Perm) -> Perm)
+            -> Result<(), MmuError> {
+        let (from, to) = self.bounds(addr, size)?;
+
+        // The null guard stays unmapped for good
+        let from = from.max(self.null_guard).min(to);
+        self.materialize(from, to)?;
+        self.save_for_rollback(from, to);
+        self.permissions[from..to].iter_mut().for_each(|x| *x = update(*x));
+        self.mark_dirty(from, to);
+        Ok(())
+    }
+
+    /// Returns the `[from, to)` byte range of the `len` long range at `addr`,
+    /// making sure it lies within the memory
+    #[inline(always)]
+    fn bounds(&self, addr: VAddr,
+              len: usize) -> Result<(usize, usize), MmuError> {
+        let to = addr.0.checked_add(len).ok_or(MmuError::Overflow)?;
+        if to > self.memory.len() {
+            return Err(MmuError::OutOfBounds { addr, len });
+        }
+        Ok((addr.0, to))
+    }
+
+    /// Returns the permissions as three bitmaps (readable, writable,
+    /// executable) with one bit per address
+    pub fn permission_bitsets(&self) -> (Vec<u64>, Vec<u64>, Vec<u64>) {
+        let perms = self.permissions();
+        let len   = perms.len().div_ceil(64);
+        let mut read  = vec![0u64; len];
+        let mut write = vec![0u64; len];
+        let mut exec  = vec![0u64; len];
+
+        for (addr, perm) in perms.iter().enumerate() {
+            let (idx, bit) = (addr / 64, addr % 64);
+            read[idx]  |= (((perm.0 & PERM_READ)  != 0) as u64) << bit;
+            write[idx] |= (((perm.0 & PERM_WRITE) != 0) as u64) << bit;
+            exec[idx]  |= (((perm.0 & PERM_EXEC)  != 0) as u64) << bit;
+        }
+
+        (read, write, exec)
+    }
+
+    /// Returns a pagemap of the memory with one entry per block, each being
+    /// a combination of `PAGEMAP_PRESENT`, `PAGEMAP_READ`, `PAGEMAP_WRITE`
+    /// and `PAGEMAP_EXEC`.
+    ///
+    /// A flag is set if any byte of the block has the corresponding
+    /// permission, a block is present if any byte has any permission.
+    pub fn pagemap(&self) -> Vec<u64> {
+        self.permissions().chunks(DIRTY_BLOCK_SIZE).map(|block| {
+            let perms = block.iter().fold(0, |acc, perm| acc | perm.0);
+
+            let mut entry = 0;
+            if perms != 0               { entry |= PAGEMAP_PRESENT; }
+            if perms & PERM_READ  != 0  { entry |= PAGEMAP_READ;    }
+            if perms & PERM_WRITE != 0  { entry |= PAGEMAP_WRITE;   }
+            if perms & PERM_EXEC  != 0  { entry |= PAGEMAP_EXEC;    }
+            entry
+        }).collect()
+    }
+
+    /// Write bytes from `buf` to memory at `addr`.
+    /// The resulting bytes marked `PERM_RAW` are set to be readable
+    /// (`PERM_READ`) instead
+    ///
+    /// With the `unchecked-access` feature the permissions aren't checked, as
+    /// for `write_unchecked()`.
+    pub fn write(&mut self, addr: VAddr, buf: &[u8]) -> Result<(), MmuError> {
+        // Check that we can write to memory
+        #[cfg(not(feature = "unchecked-access"))]
+        self.ensure_writable(addr, buf.len())?;
+        self.write_unchecked_perm(addr, buf)
+    }
+
+    /// Write bytes from `buf` to memory at `addr` without checking that it's
+    /// writable, still checking the bounds and tracking the dirty state.
+    ///
+    /// The caller has to have validated the permissions already: a write to
+    /// memory without `PERM_WRITE` succeeds here, hiding the guest bug behind
+    /// it.
+    #[inline(always)]
+    pub fn write_unchecked(&mut self, addr: VAddr,
+                           buf: &[u8]) -> Result<(), MmuError> {
+        self.write_unchecked_perm(addr, buf)
+    }
+
+    /// Write the buffers in `bufs` one after another to memory at `addr`, as
+    /// a single `write()` of their concatenation would.
+    ///
+    /// Nothing is written unless all of the combined range is writable.
+    pub fn write_vectored(&mut self, addr: VAddr,
+                          bufs: &[&[u8]]) -> Result<(), MmuError> {
+        let len = vectored_len(bufs.iter().map(|buf| buf.len()))?;
+        self.ensure_writable(addr, len)?;
+        let (from, to) = self.bounds(addr, len)?;
+        if self.touches_locked(from, to) {
+            return Err(MmuError::Locked { addr });
+        }
+        self.check_fork_budget(addr, from, to)?;
+
+        let mut cur = addr.0;
+        for buf in bufs {
+            self.write_unchecked_perm(VAddr(cur), buf)?;
+            cur += buf.len();
+        }
+        Ok(())
+    }
+
+    /// Append `buf` to the `cur_len` long buffer at `region` and re-establish
+    /// a `guard` long trailing guard without permissions right after the new
+    /// end, so overflows past it still trap. Returns the new length.
+    ///
+    /// The appended bytes are made writable regardless of their current
+    /// permissions (they're usually the previous guard), so the memory the
+    /// buffer grows into has to be reserved for it by the caller.
+    pub fn append_guarded(&mut self, region: VAddr, cur_len: usize,
+                          buf: &[u8],
+                          guard: usize) -> Result<usize, MmuError> {
+        let new_len = cur_len.checked_add(buf.len())
+            .ok_or(MmuError::Overflow)?;
+        let addr    = VAddr(region.0.checked_add(cur_len)
+            .ok_or(MmuError::Overflow)?);
+        let end     = VAddr(addr.0 + buf.len());
+
+        // Make sure the buffer and the guard fit before touching anything
+        self.bounds(addr, buf.len().checked_add(guard)
+            .ok_or(MmuError::Overflow)?)?;
+
+        self.apply_permissions(addr, buf.len(), Perm(PERM_RAW | PERM_WRITE))?;
+        self.write(addr, buf)?;
+        self.apply_permissions(end, guard, Perm(0))?;
+        Ok(new_len)
+    }
+
+    /// Copy `len` bytes at `src_addr` of the `src` MMU into the current MMU at
+    /// `dst_addr`.
+    ///
+    /// `src` is trusted, so its permissions are ignored. The copy is a regular
+    /// `write()` into the current MMU.
+    pub fn copy_region_from(&mut self, src: &Mmu, src_addr: VAddr,
+                            dst_addr: VAddr,
+                            len: usize) -> Result<(), MmuError> {
+        let (from, to) = src.bounds(src_addr, len)?;
+        self.write(dst_addr, &src.bytes(from, to))
+    }
+
+    /// Perform all the `(addr, bytes)` writes of `writes`, or none of them if
+    /// any of them would fail.
+    ///
+    /// Every target is validated up front, after which the writes are applied
+    /// in order. The blocks written to are marked dirty once each after all
+    /// the writes, so many small writes into the same block are cheap.
+    pub fn write_batch(&mut self,
+                       writes: &[(VAddr, &[u8])]) -> Result<(), MmuError> {
+        for &(addr, bytes) in writes {
+            self.ensure_writable(addr, bytes.len())?;
+            if self.touches_locked(addr.0, addr.0 + bytes.len()) {
+                return Err(MmuError::Locked { addr });
+            }
+        }
+        self.check_fork_budget_all(writes.iter()
+            .map(|&(addr, bytes)| (addr, addr.0, addr.0 + bytes.len())))?;
+
+        let mut blocks = BTreeSet::new();
+        let written = writes.iter().try_for_each(|&(addr, bytes)| {
+            let (from, to) = self.store_unmarked(addr, bytes.len(),
+                |memory| memory.copy_from_slice(bytes))?;
+            if from < to {
+                blocks.extend(from / DIRTY_BLOCK_SIZE..=
+                              (to - 1) / DIRTY_BLOCK_SIZE);
+            }
+            Ok(())
+        });
+
+        // Whatever was written has to be tracked, even on a failure
+        for block in blocks {
+            let (from, to) = self.block_range(block);
+            self.mark_dirty(from, to);
+        }
+        written
+    }
+
+    /// Write bytes from `buf` to memory at `addr`, stopping at the first byte
+    /// which can't be written to.
+    ///
+    /// Returns the number of bytes written, or on a fault the number of bytes
+    /// written before it along with the error for the faulting byte.
+    pub fn write_reporting(&mut self, addr: VAddr, buf: &[u8])
+            -> Result<usize, (usize, MmuError)> {
+        // Writable memory can still be locked, so stop at the first locked
+        // block as well
+        let mut written = self.writable_span(addr).min(buf.len());
+        let mut blocks  = addr.0 / DIRTY_BLOCK_SIZE..
+            (addr.0 + written).div_ceil(DIRTY_BLOCK_SIZE);
+        if let Some(block) = blocks.find(|&x| self.is_locked(x)) {
+            written = (block * DIRTY_BLOCK_SIZE).saturating_sub(addr.0);
+        }
+
+        self.write_unchecked_perm(addr, &buf[..written])
+            .map_err(|err| (0, err))?;
+        if written == buf.len() {
+            return Ok(written);
+        }
+
+        // Report why the next byte can't be written to
+        let at = VAddr(addr.0 + written);
+        let err = match self.ensure_writable(at, 1) {
+            Err(err) => err,
+            Ok(())   => MmuError::Locked { addr: at },
+        };
+        Err((written, err))
+    }
+
+    /// Initialize the memory at `addr` with `buf` and give it exactly the
+    /// permissions `final_perm` in one go, regardless of its current
+    /// permissions.
+    ///
+    /// Nothing is written if the region is out of bounds or locked.
+    /// `PERM_EXEC` is still subject to `set_deny_exec()`.
+    pub fn write_sealed(&mut self, addr: VAddr, buf: &[u8],
+                        final_perm: Perm) -> Result<(), MmuError> {
+        self.write_unchecked_perm(addr, buf)?;
+        self.set_permissions(addr, buf.len(), final_perm)
+    }
+
+    /// Write bytes from `buf` to memory at `addr` without checking for
+    /// `PERM_WRITE`. Bounds are still checked, the memory is still tracked as
+    /// dirty and `PERM_RAW` is still turned into `PERM_READ`.
+    ///
+    /// The caller must have validated the permissions of the whole range
+    /// beforehand (e.g. through `ensure_writable()`), otherwise this happily
+    /// writes to read-only memory.
+    pub fn write_unchecked_perm(&mut self, addr: VAddr,
+                                buf: &[u8]) -> Result<(), MmuError> {
+        self.store(addr, buf.len(), |memory| memory.copy_from_slice(buf))
+    }
+
+    /// Fill `len` bytes of memory at `addr` with `value`, as a `write()` of
+    /// that many `value`s would, without building the buffer
+    pub fn fill(&mut self, addr: VAddr, value: u8,
+                len: usize) -> Result<(), MmuError> {
+        // Check that we can write to memory
+        self.ensure_writable(addr, len)?;
+        self.store(addr, len, |memory| memory.fill(value))
+    }
+
+    /// Modify the `len` long memory at `addr` through `store`, performing
+    /// everything `write_unchecked_perm()` does around it
+    #[inline(always)]
+    fn store(&mut self, addr: VAddr, len: usize,
+             store: impl FnOnce(&mut [u8])) -> Result<(), MmuError> {
+        let (from, to) = self.store_unmarked(addr, len, store)?;
+        self.mark_dirty(from, to);
+        Ok(())
+    }
+
+    /// Perform `store()` without marking the modified memory as dirty,
+    /// returning the `[from, to)` range the caller has to mark
+    #[inline(always)]
+    fn store_unmarked(&mut self, addr: VAddr, len: usize,
+                      store: impl FnOnce(&mut [u8]))
+            -> Result<(usize, usize), MmuError> {
+        let (from, to) = self.bounds(addr, len)?;
+
+        // Locked blocks are never written to
+        if self.touches_locked(from, to) {
+            return Err(MmuError::Locked { addr });
+        }
+
+        // Write the buffer to memory
+        self.materialize(from, to)?;
+        self.save_for_rollback(from, to);
+        store(&mut self.memory[from..to]);
+
+        // Track the dirty memory
+        if let Some((line, lines)) = &mut self.cache_lines {
+            if from < to {
+                lines.extend(from / *line..=(to - 1) / *line);
+            }
+        }
+        for block in from / DIRTY_BLOCK_SIZE..to.div_ceil(DIRTY_BLOCK_SIZE) {
+            self.working_set[block / DBE_BITS] |= 1 << (block % DBE_BITS);
+        }
+
+        // RaW: Set the memory to be readable
+        self.permissions[from..to].iter_mut()
+            .filter(|x| x.0 & PERM_RAW != 0)
+            .for_each(|x| x.0 = (x.0 & !PERM_RAW) | PERM_READ);
+
+        // W^X: Written memory is no longer executable
+        if self.strict_wx {
+            self.permissions[from..to].iter_mut()
+                .for_each(|x| x.0 &= !PERM_EXEC);
+        }
+
+        if let Some(log) = &mut self.write_log {
+            log.push((addr, len));
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.writes.push((addr, self.memory[from..to].to_vec()));
+        }
+        Ok((from, to))
+    }
+
+    /// Run `f` and return its result along with the `(addr, len)` ranges of
+    /// all writes performed within it, in order
+    pub fn record_writes<R>(&mut self, f: impl FnOnce(&mut Mmu) -> R)
+            -> (R, Vec<(VAddr, usize)>) {
+        let outer = self.write_log.replace(Vec::new());
+        let ret   = f(self);
+        let log   = std::mem::replace(&mut self.write_log, outer)
+            .unwrap_or_default();
+
+        // Nested scopes still show up in the enclosing scope
+        if let Some(outer) = &mut self.write_log {
+            outer.extend_from_slice(&log);
+        }
+        (ret, log)
+    }
+
+    /// Run `f` and return its result along with a journal of all writes
+    /// performed within it, including the data written
+    pub fn journal_writes<R>(&mut self, f: impl FnOnce(&mut Mmu) -> R)
+            -> (R, WriteJournal) {
+        let outer   = self.journal.replace(WriteJournal::default());
+        let ret     = f(self);
+        let journal = std::mem::replace(&mut self.journal, outer)
+            .unwrap_or_default();
+
+        // Nested scopes still show up in the enclosing scope
+        if let Some(outer) = &mut self.journal {
+            outer.writes.extend_from_slice(&journal.writes);
+        }
+        (ret, journal)
+    }
+
+    /// Apply the first `index` writes of `journal` to the current MMU, which
+    /// is meant to be a fresh fork of the MMU the journal was recorded on.
+    ///
+    /// The writes are replayed without checking for `PERM_WRITE`, as they
+    /// already succeeded once. Useful for bisecting which write introduced a
+    /// corruption.
+    ///
+    /// Returns `MmuError::InvalidArgument` if the journal holds fewer than
+    /// `index` writes.
+    pub fn replay_up_to(&mut self, journal: &WriteJournal,
+                        index: usize) -> Result<(), MmuError> {
+        let writes = journal.writes.get(..index)
+            .ok_or(MmuError::InvalidArgument)?;
+        for (addr, bytes) in writes {
+            self.write_unchecked_perm(*addr, bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Verify that replaying `journal` onto a fork of `baseline` reproduces
+    /// the memory and permissions of the current MMU exactly.
+    ///
+    /// On failure, returns the first divergent range as `(base, size)`. A
+    /// write which can't be replayed is reported as its own range.
+    pub fn verify_journal(&self, baseline: &Mmu, journal: &WriteJournal)
+            -> Result<(), (VAddr, usize)> {
+        let mut replay = baseline.fork();
+        for (addr, bytes) in &journal.writes {
+            replay.write_unchecked_perm(*addr, bytes)
+                .map_err(|_| (*addr, bytes.len()))?;
+        }
+
+        let len = self.memory.len().max(replay.memory.len());
+        let (memory, perms) = (self.bytes(0, self.memory.len()),
+                               self.permissions());
+        let differs = |addr: usize| {
+            memory.get(addr) != replay.memory.get(addr) ||
+                perms.get(addr) != replay.permissions.get(addr)
+        };
+        let Some(base) = (0..len).find(|&addr| differs(addr)) else {
+            return Ok(());
+        };
+        let size = (base..len).take_while(|&addr| differs(addr)).count();
+        Err((VAddr(base), size))
+    }
+
+    /// Set the mask ANDed into the permissions of every access.
+    ///
+    /// This restricts all accesses regardless of the actual permissions,
+    /// e.g. to emulate dropping to a lower privilege level.
+    pub fn set_access_mask(&mut self, mask: Perm) {
+        self.access_mask = mask;
+    }
+
+    /// Enable (or disable) permission enforcement.
+    ///
+    /// While disabled, every access behaves as if it had all permissions.
+    /// Bounds are still checked and memory is still tracked as dirty. This is
+    /// meant for bring-up, to tell permission issues apart from logic bugs.
+    pub fn set_enforce_permissions(&mut self, on: bool) {
+        self.enforce_perms = on;
+    }
+
+    /// Returns the permission bits of `perm` left after the access mask, or
+    /// all of them if permissions aren't enforced
+    #[inline(always)]
+    fn masked(&self, perm: Perm) -> u8 {
+        if !self.enforce_perms {
+            return !0;
+        }
+        perm.0 & self.access_mask.0
+    }
+
+    /// Check whether a `len` long access of `kind` at `addr` would succeed,
+    /// without performing it
+    pub fn classify_access(&self, addr: VAddr, len: usize,
+                           kind: AccessKind) -> Result<(), PageFault> {
+        let fault = |addr: usize, reason| PageFault {
+            addr: VAddr(addr),
+            kind,
+            reason,
+            block: addr / DIRTY_BLOCK_SIZE,
+        };
+
+        if addr.0.checked_add(len).is_none() {
+            return Err(fault(addr.0, FaultReason::OutOfBounds));
+        }
+
+        // Anything past the end of the memory is out of bounds
+        let size  = self.memory.len();
+        let perms = self.perms(addr.0.min(size), (addr.0 + len).min(size));
+        for cur in addr.0..addr.0 + len {
+            match perms.get(cur - addr.0) {
+                None => return Err(fault(cur, FaultReason::OutOfBounds)),
+                Some(perm) if perm.0 == 0 && self.enforce_perms =>
+                    return Err(fault(cur, FaultReason::Unmapped)),
+                Some(&perm) if (self.masked(perm) & kind.perm()) == 0 =>
+                    return Err(fault(cur, FaultReason::PermissionDenied)),
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns, for each byte of the `len` long range at `addr`, whether it
+    /// lacks any of the permission bits in `perm`
+    pub fn permission_violation_mask(&self, addr: VAddr, len: usize,
+                                     perm: Perm) -> Option<Vec<bool>> {
+        let (from, to) = self.bounds(addr, len).ok()?;
+        let perms = self.perms(from, to);
+        Some(perms.iter().map(|&x| (self.masked(x) & perm.0) != perm.0)
+            .collect())
+    }
+
+    /// Check that every byte of the `len` long range at `addr` has all of
+    /// the permission bits in `perm`.
+    ///
+    /// On failure, reports the first faulting address along with the bits it
+    /// lacks, or the whole range if it doesn't fit in memory.
+    pub fn check_access(&self, addr: VAddr, len: usize,
+                        perm: Perm) -> Result<(), MmuError> {
+        let (from, to) = self.bounds(addr, len)?;
+        for (cur, &have) in self.perms(from, to).iter().enumerate() {
+            let have = self.masked(have);
+            if have & perm.0 != perm.0 {
+                return Err(MmuError::PermissionDenied {
+                    addr:    VAddr(from + cur),
+                    missing: Perm(perm.0 & !have),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of consecutive bytes starting at `addr` which have
+    /// all of the permission bits in `perm`
+    fn perm_span(&self, addr: VAddr, perm: Perm) -> usize {
+        if addr.0 >= self.memory.len() {
+            return 0;
+        }
+        self.perms(addr.0, self.memory.len()).iter()
+            .take_while(|&&x| self.masked(x) & perm.0 == perm.0)
+            .count()
+    }
+
+    /// Returns the number of consecutive readable bytes starting at `addr`
+    pub fn readable_span(&self, addr: VAddr) -> usize {
+        self.perm_span(addr, Perm(PERM_READ))
+    }
+
+    /// Returns the number of consecutive writable bytes starting at `addr`
+    pub fn writable_span(&self, addr: VAddr) -> usize {
+        self.perm_span(addr, Perm(PERM_WRITE))
+    }
+
+    /// Returns the number of consecutive executable bytes starting at `addr`
+    pub fn executable_span(&self, addr: VAddr) -> usize {
+        self.perm_span(addr, Perm(PERM_EXEC))
+    }
+
+    /// Make sure that the whole `size` long memory block starting from `addr`
+    /// has all of the permission bits in `perm`
+    pub fn ensure_perms(&self, addr: VAddr, size: usize,
+                        perm: Perm) -> Result<(), MmuError> {
+        self.check_access(addr, size, perm)
+    }
+
+    /// Make sure that the whole `size` long memory block starting from `addr`
+    /// is writable
+    pub fn ensure_writable(&self, addr: VAddr,
+                           size: usize) -> Result<(), MmuError> {
+        self.ensure_perms(addr, size, Perm(PERM_WRITE))
+    }
+
+    /// Track the memory in range `[from, to)` as dirty
+    fn mark_dirty(&mut self, from: usize, to: usize) {
+        if from >= to {
+            return;
+        }
+
+        let dirty_start = from / DIRTY_BLOCK_SIZE;
+        let dirty_end   = (to - 1) / DIRTY_BLOCK_SIZE;
+        for dirty_block in dirty_start..=dirty_end {
+            // Locked blocks are never restored
+            if self.is_locked(dirty_block) {
+                continue;
+            }
+
+            let idx = dirty_block / DBE_BITS;
+            let bit = dirty_block % DBE_BITS;
+
+            // Only change the dirty state if the block isn't dirty already
+            if self.dirty_bitmap[idx] & (1 << bit) == 0 {
+                self.dirty_indexes.push(dirty_block);
+                self.dirty_bitmap[idx] |= 1 << bit;
+            }
+        }
+    }
+
+    /// Reads bytes from memory at `addr` to `buf`.
+    ///
+    /// With the `unchecked-access` feature the permissions aren't checked, as
+    /// for `read_unchecked()`.
+    pub fn read(&self, addr: VAddr, buf: &mut [u8]) -> Result<(), MmuError> {
+        // Check that we can read from the memory
+        #[cfg(not(feature = "unchecked-access"))]
+        self.check_access(addr, buf.len(), Perm(PERM_READ))?;
+        self.read_unchecked(addr, buf)
+    }
+
+    /// Reads bytes from memory at `addr` to `buf` without checking that it's
+    /// readable, still checking the bounds.
+    ///
+    /// The caller has to have validated the permissions already: a read of
+    /// uninitialized (`PERM_RAW`) or unmapped memory succeeds here, hiding
+    /// the guest bug behind it.
+    #[inline(always)]
+    pub fn read_unchecked(&self, addr: VAddr,
+                          buf: &mut [u8]) -> Result<(), MmuError> {
+        let (from, to) = self.bounds(addr, buf.len())?;
+        buf.copy_from_slice(&self.bytes(from, to));
+        Ok(())
+    }
+
+    /// Read memory at `addr` into the buffers in `bufs` one after another, as
+    /// a single `read()` into their concatenation would.
+    ///
+    /// Nothing is read unless all of the combined range is readable.
+    pub fn read_vectored(&self, addr: VAddr,
+                         bufs: &mut [&mut [u8]]) -> Result<(), MmuError> {
+        let len = vectored_len(bufs.iter().map(|buf| buf.len()))?;
+        self.check_access(addr, len, Perm(PERM_READ))?;
+
+        let mut cur = addr.0;
+        for buf in bufs {
+            buf.copy_from_slice(&self.bytes(cur, cur + buf.len()));
+            cur += buf.len();
+        }
+        Ok(())
+    }
+
+    /// Reads a `bit_len` long bitfield (up to 64 bits) starting `bit_offset`
+    /// bits into the memory at `addr`.
+    ///
+    /// The bytes covering the bitfield are interpreted as an integer in the
+    /// `endian` byte order. For `Endian::Little` bits are counted from the
+    /// least significant bit of the first byte, for `Endian::Big` from the
+    /// most significant one.
+    pub fn read_bits(&self, addr: VAddr, bit_offset: usize, bit_len: usize,
+                     endian: Endian) -> Result<u64, MmuError> {
+        let (start, nbytes, shift) = bitfield_span(addr, bit_offset, bit_len,
+                                                   endian)
+            .ok_or(MmuError::InvalidArgument)?;
+        let mut buf = [0u8; 16];
+        self.read(start, &mut buf[..nbytes])?;
+
+        let raw  = bitfield_decode(&buf, nbytes, endian);
+        let mask = (1u128 << bit_len) - 1;
+        Ok(((raw >> shift) & mask) as u64)
+    }
+
+    /// Writes the low `bit_len` bits of `value` into the bitfield described
+    /// by the same arguments as for `read_bits()`.
+    ///
+    /// This is a read-modify-write, so the bytes covering the bitfield have
+    /// to be both readable and writable.
+    pub fn write_bits(&mut self, addr: VAddr, bit_offset: usize,
+                      bit_len: usize, endian: Endian,
+                      value: u64) -> Result<(), MmuError> {
+        let (start, nbytes, shift) = bitfield_span(addr, bit_offset, bit_len,
+                                                   endian)
+            .ok_or(MmuError::InvalidArgument)?;
+        let mut buf = [0u8; 16];
+        self.read(start, &mut buf[..nbytes])?;
+
+        // Replace the bitfield
+        let mask = ((1u128 << bit_len) - 1) << shift;
+        let raw  = (bitfield_decode(&buf, nbytes, endian) & !mask) |
+            (((value as u128) << shift) & mask);
+
+        let buf = match endian {
+            Endian::Little => raw.to_le_bytes(),
+            Endian::Big    => (raw << (128 - nbytes * 8)).to_be_bytes(),
+        };
+        self.write(start, &buf[..nbytes])
+    }
+
+    /// Reads a `u8` from `addr`
+    pub fn read_u8(&self, addr: VAddr) -> Result<u8, MmuError> {
+        let mut buf = [0u8; 1];
+        self.read(addr, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    /// Reads a `u16` in the `endian` byte order from `addr`
+    pub fn read_u16(&self, addr: VAddr,
+                    endian: Endian) -> Result<u16, MmuError> {
+        let mut buf = [0u8; 2];
+        self.read(addr, &mut buf)?;
+        Ok(match endian {
+            Endian::Little => u16::from_le_bytes(buf),
+            Endian::Big    => u16::from_be_bytes(buf),
+        })
+    }
+
+    /// Reads a `u32` in the `endian` byte order from `addr`
+    pub fn read_u32(&self, addr: VAddr,
+                    endian: Endian) -> Result<u32, MmuError> {
+        let mut buf = [0u8; 4];
+        self.read(addr, &mut buf)?;
+        Ok(match endian {
+            Endian::Little => u32::from_le_bytes(buf),
+            Endian::Big    => u32::from_be_bytes(buf),
+        })
+    }
+
+    /// Reads a `u64` in the `endian` byte order from `addr`
+    pub fn read_u64(&self, addr: VAddr,
+                    endian: Endian) -> Result<u64, MmuError> {
+        let mut buf = [0u8; 8];
+        self.read(addr, &mut buf)?;
+        Ok(match endian {
+            Endian::Little => u64::from_le_bytes(buf),
+            Endian::Big    => u64::from_be_bytes(buf),
+        })
+    }
+
+    /// Writes `value` as a `u8` to `addr`
+    pub fn write_u8(&mut self, addr: VAddr, value: u8) -> Result<(), MmuError> {
+        self.write(addr, &[value])
+    }
+
+    /// Writes `value` as a `u16` in the `endian` byte order to `addr`
+    pub fn write_u16(&mut self, addr: VAddr, endian: Endian,
+                     value: u16) -> Result<(), MmuError> {
+        let buf = match endian {
+            Endian::Little => value.to_le_bytes(),
+            Endian::Big    => value.to_be_bytes(),
+        };
+        self.write(addr, &buf)
+    }
+
+    /// Writes `value` as a `u32` in the `endian` byte order to `addr`
+    pub fn write_u32(&mut self, addr: VAddr, endian: Endian,
+                     value: u32) -> Result<(), MmuError> {
+        let buf = match endian {
+            Endian::Little => value.to_le_bytes(),
+            Endian::Big    => value.to_be_bytes(),
+        };
+        self.write(addr, &buf)
+    }
+
+    /// Writes `value` as a `u64` in the `endian` byte order to `addr`
+    pub fn write_u64(&mut self, addr: VAddr, endian: Endian,
+                     value: u64) -> Result<(), MmuError> {
+        let buf = match endian {
+            Endian::Little => value.to_le_bytes(),
+            Endian::Big    => value.to_be_bytes(),
+        };
+        self.write(addr, &buf)
+    }
+
+    /// Reads a `u32` in the host byte order from `addr`
+    pub fn read_u32_ne(&self, addr: VAddr) -> Result<u32, MmuError> {
+        self.read_u32(addr, Endian::NATIVE)
+    }
+
+    /// Reads a `u64` in the host byte order from `addr`
+    pub fn read_u64_ne(&self, addr: VAddr) -> Result<u64, MmuError> {
+        self.read_u64(addr, Endian::NATIVE)
+    }
+
+    /// Writes `value` as a `u32` in the host byte order to `addr`
+    pub fn write_u32_ne(&mut self, addr: VAddr,
+                        value: u32) -> Result<(), MmuError> {
+        self.write_u32(addr, Endian::NATIVE, value)
+    }
+
+    /// Writes `value` as a `u64` in the host byte order to `addr`
+    pub fn write_u64_ne(&mut self, addr: VAddr,
+                        value: u64) -> Result<(), MmuError> {
+        self.write_u64(addr, Endian::NATIVE, value)
+    }
+
+    /// Reads the `width` byte (1, 2, 4 or 8) field at `base + offset` in the
+    /// `endian` byte order, zero-extended to a `u64`
+    pub fn read_field(&self, base: VAddr, offset: usize, width: usize,
+                      endian: Endian) -> Option<u64> {
+        if !matches!(width, 1 | 2 | 4 | 8) {
+            return None;
+        }
+        let addr = VAddr(base.0.checked_add(offset)?);
+
+        let mut buf = [0u8; 8];
+        Some(match endian {
+            Endian::Little => {
+                self.read(addr, &mut buf[..width]).ok()?;
+                u64::from_le_bytes(buf)
+            },
+            Endian::Big => {
+                self.read(addr, &mut buf[8 - width..]).ok()?;
+                u64::from_be_bytes(buf)
+            },
+        })
+    }
+
+    /// Writes `value` as the `width` byte (1, 2, 4 or 8) field at
+    /// `base + offset` in the `endian` byte order.
+    ///
+    /// Returns `None` without writing anything if `value` doesn't fit in
+    /// `width` bytes.
+    pub fn write_field(&mut self, base: VAddr, offset: usize, width: usize,
+                       endian: Endian, value: u64) -> Option<()> {
+        if !matches!(width, 1 | 2 | 4 | 8) ||
+                (width < 8 && value >> (width * 8) != 0) {
+            return None;
+        }
+        let addr = VAddr(base.0.checked_add(offset)?);
+
+        match endian {
+            Endian::Little => self.write(addr, &value.to_le_bytes()[..width]),
+            Endian::Big    => self.write(addr,
+                                         &value.to_be_bytes()[8 - width..]),
+        }.ok()
+    }
+
+    /// Follows a linked list of nodes starting at `head`, returning the
+    /// addresses of the nodes visited.
+    ///
+    /// The `next` pointer of every node is a `u64` in the `endian` byte order
+    /// at `node + next_off`. The walk stops at a NULL pointer or after `max`
+    /// nodes. Fails if a node is unmapped or a `next` pointer can't be read,
+    /// and with `MmuError::Cycle` if the list loops back onto itself.
+    pub fn follow_chain(&self, head: VAddr, next_off: usize, endian: Endian,
+                        max: usize) -> Result<Vec<VAddr>, MmuError> {
+        let mut nodes   = Vec::new();
+        let mut visited = BTreeSet::new();
+        let mut node    = head;
+
+        while node.0 != 0 && nodes.len() < max {
+            // Detect cycles and unmapped nodes
+            if !visited.insert(node) {
+                return Err(MmuError::Cycle { addr: node });
+            }
+            let perm = self.perm_at(node.0)
+                .ok_or(MmuError::OutOfBounds { addr: node, len: 1 })?;
+            if perm.0 == 0 {
+                return Err(MmuError::PermissionDenied {
+                    addr:    node,
+                    missing: Perm(PERM_READ),
+                });
+            }
+            nodes.push(node);
+
+            let next = node.0.checked_add(next_off).ok_or(MmuError::Overflow)?;
+            let next = self.read_u64(VAddr(next), endian)?;
+            node = VAddr(usize::try_from(next)
+                .map_err(|_| MmuError::Overflow)?);
+        }
+
+        Ok(nodes)
+    }
+
+    /// Returns an iterator over `count` consecutive `T`s in memory starting
+    /// at `addr`, decoded in the host byte order.
+    ///
+    /// The whole range is checked for `PERM_READ` once, up front.
+    pub fn iter_pod<T: Pod>(&self, addr: VAddr, count: usize)
+            -> Result<impl Iterator<Item = T> + '_, MmuError> {
+        let size = std::mem::size_of::<T>();
+        let len  = count.checked_mul(size).ok_or(MmuError::Overflow)?;
+
+        // Check that we can read from the memory
+        self.check_access(addr, len, Perm(PERM_READ))?;
+
+        let bytes = self.bytes(addr.0, addr.0 + len);
+        Ok((0..count).map(move |ii| {
+            let chunk = &bytes[ii * size..(ii + 1) * size];
+
+            // SAFETY: `chunk` is exactly `size_of::<T>()` bytes long and `T`
+            // is valid for any bit pattern
+            unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const T) }
+        }))
+    }
+
+    /// Fetches instruction bytes from memory at `addr` to `buf`.
+    ///
+    /// This behaves like `read()` but requires `PERM_EXEC` instead of
+    /// `PERM_READ`. The first fetch from a block invokes the first fetch hook.
+    pub fn fetch(&self, addr: VAddr, buf: &mut [u8]) -> Result<(), MmuError> {
+        // Check that we can execute the memory
+        self.check_access(addr, buf.len(), Perm(PERM_EXEC))?;
+
+        // Fetch the memory
+        let (from, to) = (addr.0, addr.0 + buf.len());
+        buf.copy_from_slice(&self.bytes(from, to));
+
+        // Track the executed blocks
+        if from < to {
+            for block in from / DIRTY_BLOCK_SIZE..=(to - 1) / DIRTY_BLOCK_SIZE {
+                let word = &self.exec_bitmap[block / 64];
+                let bit  = 1 << (block % 64);
+
+                // Only the fetch which actually sets the bit invokes the hook
+                if word.load(Ordering::Relaxed) & bit == 0 &&
+                        word.fetch_or(bit, Ordering::Relaxed) & bit == 0 {
+                    if let Some(hook) = &self.fetch_hook {
+                        let mut hook = hook.lock()
+                            .unwrap_or_else(PoisonError::into_inner);
+                        hook(VAddr(block * DIRTY_BLOCK_SIZE));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the base and the contents of the whole block containing `addr`
+    /// for caching decoded instructions, if the entire block is executable
+    pub fn exec_block(&self, addr: VAddr) -> Option<(VAddr, &[u8])> {
+        if addr.0 >= self.memory.len() {
+            return None;
+        }
+
+        let (from, to) = self.block_range(addr.0 / DIRTY_BLOCK_SIZE);
+        let owner = self.block_owner(addr.0 / DIRTY_BLOCK_SIZE);
+        if owner.permissions[from..to].iter()
+                .any(|&x| (self.masked(x) & PERM_EXEC) == 0) {
+            return None;
+        }
+        Some((VAddr(from), &owner.memory[from..to]))
+    }
+
+    /// Set the hook invoked with the base of a block the first time it's
+    /// fetched from since the last fork or reset.
+    ///
+    /// The hook isn't inherited by forks.
+    pub fn set_on_first_fetch(&mut self,
+                              hook: impl FnMut(VAddr) + Send + Sync + 'static) {
+        self.fetch_hook = Some(Mutex::new(Box::new(hook)));
+    }
+
+    /// Reads bytes from memory at `addr` to `buf`, filling every byte that is
+    /// unreadable or out of bounds with `default` instead of failing
+    pub fn read_lenient(&self, addr: VAddr, buf: &mut [u8], default: u8) {
+        for (ii, byte) in buf.iter_mut().enumerate() {
+            let readable = addr.0.checked_add(ii)
+                .filter(|&idx| self.perm_at(idx)
+                    .is_some_and(|x| (self.masked(x) & PERM_READ) != 0));
+
+            *byte = readable.map_or(default, |idx| {
+                self.block_owner(idx / DIRTY_BLOCK_SIZE).memory[idx]
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSG: &[u8] = b"This is some text that is written into the memory.";
+
+    /// Whether `read()` and `write()` check the permissions, so that the
+    /// faults they report can be asserted
+    const CHECKED: bool = !cfg!(feature = "unchecked-access");
+
+    #[test]
+    fn byte_perfect_allocation() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        mem.allocate(MSG.len()).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_memory() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        mem.allocate(1024).unwrap(); // OK
+        mem.allocate(1024).unwrap(); // OK
+        mem.allocate(1024).unwrap(); // OK
+        mem.allocate(1024).unwrap(); // OK
+        mem.allocate(1).unwrap();    // PANIC
+    }
+
+    #[test]
+    fn read_write() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let mut buf = [0; MSG.len()];
+        mem.allocate(MSG.len()).unwrap();
+        mem.write(VAddr(0x0), MSG).unwrap();
+        mem.read(VAddr(0x0), &mut buf).unwrap();
+        assert!(buf[0..MSG.len()] == *MSG);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "unchecked-access"), should_panic)]
+    fn read_uninitialized_memory() {
+        let mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let mut buf = [0; MSG.len()];
+        mem.read(VAddr(0x0), &mut buf).unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "unchecked-access"), should_panic)]
+    fn write_unallocated_memory() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        mem.write(VAddr(0x0), MSG).unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "unchecked-access"), should_panic)]
+    fn reset_permissions() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(DIRTY_BLOCK_SIZE).unwrap();
+        {
+            let mut buf = [0; DIRTY_BLOCK_SIZE];
+            let mut new_mem = mem.fork();
+
+            // Here we write to the memory, so we set `PERM_READ`.
+            // Consecutive read operations shouldn't panic.
+            assert!(new_mem.write(base, &buf).is_ok());
+            assert!(new_mem.read(base, &mut buf).is_ok());
+
+            // When the memory is reset, permissions are reset as well.
+            // Since we haven't written to the memory yet, we can't read it.
+            new_mem.reset(&mem);
+            new_mem.read(base, &mut buf).unwrap();
+        }
+    }
+
+    #[test]
+    fn truly_dirty_blocks_skip_restored_bytes() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let base = mem.allocate(DIRTY_BLOCK_SIZE * 2).unwrap();
+        let other = VAddr(base.0 + DIRTY_BLOCK_SIZE);
+        mem.write(base, MSG).unwrap();
+        mem.write(other, MSG).unwrap();
+
+        let mut new_mem = mem.fork();
+
+        // Change the first block, then write the original bytes back
+        new_mem.write(base, b"asdf").unwrap();
+        new_mem.write(base, &MSG[..4]).unwrap();
+
+        // Genuinely change the second block
+        new_mem.write(other, b"asdf").unwrap();
+
+        assert_eq!(new_mem.dirty_indexes.len(), 2);
+        assert_eq!(new_mem.truly_dirty_blocks(&mem), vec![1]);
+    }
+
+    #[test]
+    fn setup_thread() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        let thread = mem.setup_thread(DIRTY_BLOCK_SIZE, 64).unwrap();
+
+        // The stack is writable right below the stack pointer
+        assert!(mem.write(VAddr(thread.sp.0 - 8), b"asdfasdf").is_ok());
+
+        // Overflowing the stack into the guard page traps
+        let stack_bottom = thread.guard.0 + THREAD_GUARD_SIZE;
+        assert!(!CHECKED || mem.write(VAddr(stack_bottom - 1), b"a").is_err());
+        assert!(mem.write(VAddr(stack_bottom), b"a").is_ok());
+
+        // The TLS is zeroed and readable
+        let mut buf = [0xff; 64];
+        mem.read(thread.tls_base, &mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 0));
+
+        // The default allocation permissions don't matter
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        mem.set_default_alloc_perm(Perm(PERM_READ));
+        let thread = mem.setup_thread(DIRTY_BLOCK_SIZE, 64).unwrap();
+        assert!(mem.write(VAddr(thread.sp.0 - 8), b"asdfasdf").is_ok());
+        mem.read(thread.tls_base, &mut buf).unwrap();
+    }
+
+    #[test]
+    fn suballocate_within_parent() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let parent = mem.allocate(256).unwrap();
+
+        // Sub-ranges within the parent are fine
+        let child = mem.suballocate(parent, 16, 32).unwrap();
+        assert!(child == VAddr(parent.0 + 16));
+        assert!(mem.suballocate(parent, 0, 256).is_ok());
+
+        // Sub-ranges crossing the end of the parent are not
+        assert!(mem.suballocate(parent, 250, 16).is_err());
+        assert!(mem.suballocate(parent, usize::MAX, 2).is_err());
+
+        // Neither are sub-ranges of something that was never allocated
+        assert!(mem.suballocate(VAddr(parent.0 + 16), 0, 1).is_err());
+
+        let children = mem.children(parent).unwrap();
+        assert_eq!(children.len(), 2);
+        assert!(children[0] == (child, 32));
+    }
+
+    #[test]
+    fn read_lenient_fills_gaps() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(8).unwrap();
+        mem.write(base, b"asdf").unwrap();
+
+        // The unwritten half of the allocation isn't readable
+        let mut buf = [0; 8];
+        mem.read_lenient(base, &mut buf, 0x41);
+        assert_eq!(&buf, b"asdfAAAA");
+
+        // Out of range bytes are filled as well
+        let mut buf = [0; 4];
+        mem.read_lenient(VAddr(DIRTY_BLOCK_SIZE - 2), &mut buf, 0x41);
+        assert_eq!(&buf, b"AAAA");
+        mem.read_lenient(VAddr(usize::MAX), &mut buf, 0x42);
+        assert_eq!(&buf, b"BBBB");
+    }
+
+    #[test]
+    fn permission_bitsets() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        mem.set_permissions(VAddr(0), 4, Perm(PERM_READ)).unwrap();
+        mem.set_permissions(VAddr(62), 4, Perm(PERM_WRITE)).unwrap();
+        mem.set_permissions(VAddr(64), 1, Perm(PERM_READ | PERM_EXEC))
+            .unwrap();
+
+        let (read, write, exec) = mem.permission_bitsets();
+        assert_eq!(read.len(), DIRTY_BLOCK_SIZE / 64);
+        assert_eq!(read[0], 0b1111);
+        assert_eq!(read[1], 0b1);
+        assert_eq!(write[0], 0b11 << 62);
+        assert_eq!(write[1], 0b10);
+        assert_eq!(exec[0], 0);
+        assert_eq!(exec[1], 0b1);
+        assert!(read[2..].iter().chain(&write[2..]).all(|&x| x == 0));
+    }
+
+    #[test]
+    fn reset_totals_accumulate() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        let base = mem.allocate(DIRTY_BLOCK_SIZE * 4).unwrap();
+        let mut new_mem = mem.fork();
+
+        for ii in 0..3 {
+            // Dirty `ii + 1` separate blocks
+            for block in 0..=ii {
+                let addr = VAddr(base.0 + block * DIRTY_BLOCK_SIZE);
+                new_mem.write(addr, b"asdf").unwrap();
+            }
+            new_mem.reset(&mem);
+        }
+
+        assert_eq!(new_mem.reset_totals(), ResetCumulative {
+            resets: 3,
+            blocks: 6,
+            bytes:  6 * DIRTY_BLOCK_SIZE,
+            copies: 3,
+        });
+
+        new_mem.clear_reset_totals();
+        assert_eq!(new_mem.reset_totals(), ResetCumulative::default());
+    }
+
+    #[test]
+    fn write_unchecked_perm_tracks_dirty() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let base = mem.allocate(DIRTY_BLOCK_SIZE * 2).unwrap();
+        let mut new_mem = mem.fork();
+
+        // Validate the whole region once, then write without the checks
+        let addr = VAddr(base.0 + DIRTY_BLOCK_SIZE);
+        new_mem.ensure_writable(base, DIRTY_BLOCK_SIZE * 2).unwrap();
+        new_mem.write_unchecked_perm(addr, b"asdf").unwrap();
+        assert!(new_mem.dirty_indexes == [1]);
+
+        // The written bytes are readable and get reset as usual
+        let mut buf = [0; 4];
+        new_mem.read(addr, &mut buf).unwrap();
+        assert_eq!(&buf, b"asdf");
+        new_mem.reset(&mem);
+        assert!(!CHECKED || new_mem.read(addr, &mut buf).is_err());
+
+        // Bounds are still checked
+        assert!(new_mem.ensure_writable(base, DIRTY_BLOCK_SIZE * 3).is_err());
+        assert!(new_mem.write_unchecked_perm(
+            VAddr(DIRTY_BLOCK_SIZE * 2 - 2), b"asdf").is_err());
+    }
+
+    #[test]
+    fn access_mask_restricts_writes() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(MSG.len()).unwrap();
+        mem.write(base, MSG).unwrap();
+
+        // Drop the write permission from every access
+        mem.set_access_mask(Perm(!PERM_WRITE));
+        let mut buf = [0; MSG.len()];
+        assert!(!CHECKED || mem.write(base, MSG).is_err());
+        assert!(mem.read(base, &mut buf).is_ok());
+        assert!(buf == *MSG);
+
+        // Lifting the mask restores the permissions
+        mem.set_access_mask(Perm(!0));
+        assert!(mem.write(base, MSG).is_ok());
+    }
+
+    #[test]
+    fn apply_extracted_patch() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        let base = mem.allocate(DIRTY_BLOCK_SIZE * 4).unwrap();
+
+        let mut new_mem = mem.fork();
+        new_mem.write(base, MSG).unwrap();
+        new_mem.write(VAddr(base.0 + DIRTY_BLOCK_SIZE * 2), MSG).unwrap();
+        let patch = new_mem.extract_dirty();
+        assert_eq!(patch.blocks.len(), 2);
+
+        // Applying the patch to a fresh fork gives the same state
+        let mut other = mem.fork();
+        other.apply_patch(&patch).unwrap();
+        assert!(other.memory == new_mem.memory);
+        assert!(other.permissions == new_mem.permissions);
+
+        // The patched blocks are dirty and get reset
+        other.reset(&mem);
+        assert!(other.memory == mem.memory);
+
+        // Patches with blocks past the end of memory are rejected whole
+        let mut small = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        assert_eq!(small.apply_patch(&patch), Err(MmuError::OutOfBounds {
+            addr: VAddr(DIRTY_BLOCK_SIZE * 2),
+            len:  DIRTY_BLOCK_SIZE,
+        }));
+        assert!(small.dirty_indexes.is_empty());
+
+        // Even if the block indexes are absurd
+        let bogus = DirtyPatch {
+            blocks: vec![(usize::MAX / 2, Vec::new(), Vec::new())],
+        };
+        assert_eq!(small.apply_patch(&bogus), Err(MmuError::OutOfBounds {
+            addr: VAddr(usize::MAX),
+            len:  0,
+        }));
+    }
+
+    #[test]
+    fn record_writes_in_scope() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(256).unwrap();
+
+        // Writes outside of the scope aren't recorded
+        mem.write(base, MSG).unwrap();
+
+        let (ret, log) = mem.record_writes(|mem| {
+            mem.write(VAddr(base.0 + 16), b"asdf").unwrap();
+            mem.write(VAddr(base.0 + 128), MSG).unwrap();
+
+            // Failed writes aren't recorded either
+            assert!(!CHECKED ||
+                    mem.write(VAddr(base.0 + 255), b"asdf").is_err());
+            1337
+        });
+
+        assert_eq!(ret, 1337);
+        assert!(log == [(VAddr(base.0 + 16), 4),
+                        (VAddr(base.0 + 128), MSG.len())]);
+        assert!(mem.write_log.is_none());
+    }
+
+    #[test]
+    fn first_fetch_hook_fires_once() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let base = mem.allocate(DIRTY_BLOCK_SIZE).unwrap();
+        mem.set_permissions(base, 64, Perm(PERM_READ | PERM_EXEC)).unwrap();
+        let mut new_mem = mem.fork();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let hook_fired = fired.clone();
+        new_mem.set_on_first_fetch(move |addr| {
+            assert!(addr == base);
+            hook_fired.fetch_add(1, Ordering::SeqCst);
+        });
+
+        // Only the first fetch from the block fires the hook
+        let mut buf = [0; 4];
+        new_mem.fetch(base, &mut buf).unwrap();
+        new_mem.fetch(VAddr(base.0 + 16), &mut buf).unwrap();
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+
+        // Non-executable memory can't be fetched from
+        assert!(new_mem.fetch(VAddr(base.0 + 62), &mut buf).is_err());
+
+        // Resetting forgets the executed blocks
+        new_mem.reset(&mem);
+        new_mem.fetch(base, &mut buf).unwrap();
+        assert_eq!(fired.load(Ordering::SeqCst), 2);
+
+        // Fetches only need a shared reference, even from several threads
+        new_mem.reset(&mem);
+        let shared = &new_mem;
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(move || {
+                    let mut buf = [0; 4];
+                    shared.fetch(base, &mut buf).unwrap();
+                });
+            }
+        });
+        assert_eq!(fired.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn allocated_and_mapped_totals() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        mem.allocate(1).unwrap();
+        mem.allocate(17).unwrap();
+        mem.allocate(100).unwrap();
+        assert_eq!(mem.total_allocated(), 118);
+        assert_eq!(mem.total_mapped(), 118);
+
+        // Guard pages are allocated but not mapped
+        mem.setup_thread(256, 64).unwrap();
+        assert_eq!(mem.total_allocated(), 118 + THREAD_GUARD_SIZE + 256 + 64);
+        assert_eq!(mem.total_mapped(), 118 + 256 + 64);
+    }
+
+    #[test]
+    fn reset_to_zero() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let base = mem.allocate(DIRTY_BLOCK_SIZE * 2).unwrap();
+        let other = VAddr(base.0 + DIRTY_BLOCK_SIZE);
+        mem.write(base, MSG).unwrap();
+        mem.write(other, MSG).unwrap();
+
+        mem.reset_to_zero();
+        assert!(mem.dirty_indexes.is_empty());
+        assert!(mem.dirty_bitmap.iter().all(|&x| x == 0));
+
+        // The dirty blocks read back as zero with no permissions
+        assert!(mem.memory.iter().all(|&x| x == 0));
+        assert!(mem.permissions.iter().all(|x| x.0 == 0));
+        let mut buf = [0; MSG.len()];
+        assert!(!CHECKED || mem.read(base, &mut buf).is_err());
+        mem.read_lenient(other, &mut buf, 0);
+        assert!(buf.iter().all(|&x| x == 0));
+    }
+
+    /// Build a minimal ELF64 with a single `PT_LOAD` segment holding `data`
+    /// at `vaddr`
+    fn build_elf(vaddr: usize, data: &[u8]) -> Vec<u8> {
+        let mut elf = vec![0u8; 0x40 + 0x38];
+        elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        elf[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&0x38u16.to_le_bytes());
+        elf[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes());
+
+        let phdr = &mut elf[0x40..];
+        phdr[0x00..0x04].copy_from_slice(&1u32.to_le_bytes());
+        phdr[0x04..0x08].copy_from_slice(&5u32.to_le_bytes());
+        phdr[0x08..0x10].copy_from_slice(&0x78u64.to_le_bytes());
+        phdr[0x10..0x18].copy_from_slice(&(vaddr as u64).to_le_bytes());
+        phdr[0x20..0x28].copy_from_slice(&(data.len() as u64).to_le_bytes());
+        phdr[0x28..0x30].copy_from_slice(&(data.len() as u64).to_le_bytes());
+
+        elf.extend_from_slice(data);
+        elf
+    }
+
+    #[test]
+    fn verify_segments() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(MSG.len()).unwrap();
+        mem.write(base, MSG).unwrap();
+
+        let elf = build_elf(base.0, MSG);
+        assert_eq!(mem.verify_segments(&elf), Ok(()));
+
+        // Mutating a loaded byte is caught
+        mem.write(VAddr(base.0 + 5), b"X").unwrap();
+        assert_eq!(mem.verify_segments(&elf),
+                   Err(VerifyError::Mismatch(VAddr(base.0 + 5))));
+
+        // As are segments outside of memory and broken files
+        let elf = build_elf(DIRTY_BLOCK_SIZE - 1, MSG);
+        assert_eq!(mem.verify_segments(&elf),
+                   Err(VerifyError::OutOfBounds(VAddr(DIRTY_BLOCK_SIZE - 1))));
+        assert_eq!(mem.verify_segments(&elf[..0x50]),
+                   Err(VerifyError::Malformed));
+    }
+
+    #[test]
+    fn classify_access() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let base = mem.allocate(DIRTY_BLOCK_SIZE * 2).unwrap();
+        mem.write(base, MSG).unwrap();
+
+        assert_eq!(mem.classify_access(base, MSG.len(), AccessKind::Read),
+                   Ok(()));
+
+        // Allocated but never written memory lacks `PERM_READ`
+        let fault = mem.classify_access(base, MSG.len() + 1, AccessKind::Read)
+            .unwrap_err();
+        assert_eq!(fault.addr, VAddr(base.0 + MSG.len()));
+        assert_eq!(fault.kind, AccessKind::Read);
+        assert_eq!(fault.reason, FaultReason::PermissionDenied);
+        assert_eq!(fault.block, 0);
+
+        // Memory without any permissions is unmapped
+        let addr = VAddr(base.0 + DIRTY_BLOCK_SIZE + 8);
+        mem.set_permissions(addr, 8, Perm(0)).unwrap();
+        let fault = mem.classify_access(VAddr(addr.0 - 4), 8, AccessKind::Exec)
+            .unwrap_err();
+        assert_eq!(fault.addr, VAddr(addr.0 - 4));
+        assert_eq!(fault.reason, FaultReason::PermissionDenied);
+        let fault = mem.classify_access(VAddr(addr.0 - 4), 8, AccessKind::Write)
+            .unwrap_err();
+        assert_eq!(fault.addr, addr);
+        assert_eq!(fault.reason, FaultReason::Unmapped);
+        assert_eq!(fault.block, 1);
+
+        // Accesses past the end of memory are out of bounds
+        let fault = mem.classify_access(VAddr(DIRTY_BLOCK_SIZE * 2 - 1), 2,
+                                        AccessKind::Write).unwrap_err();
+        assert_eq!(fault.addr, VAddr(DIRTY_BLOCK_SIZE * 2));
+        assert_eq!(fault.reason, FaultReason::OutOfBounds);
+        let fault = mem.classify_access(VAddr(usize::MAX), 2, AccessKind::Read)
+            .unwrap_err();
+        assert_eq!(fault.reason, FaultReason::OutOfBounds);
+    }
+
+    #[test]
+    fn reset_coalesces_adjacent_blocks() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 8);
+        let base = mem.allocate(DIRTY_BLOCK_SIZE * 8).unwrap();
+        let mut new_mem = mem.fork();
+
+        // Dirty blocks 5, 1, 2, 3 and 7 in a scattered order
+        for block in [5, 2, 7, 1, 3] {
+            let addr = VAddr(base.0 + block * DIRTY_BLOCK_SIZE + 8);
+            new_mem.write(addr, MSG).unwrap();
+        }
+        new_mem.reset(&mem);
+
+        // Blocks 1-3 are restored with a single copy
+        let totals = new_mem.reset_totals();
+        assert_eq!(totals.blocks, 5);
+        assert_eq!(totals.copies, 3);
+        assert!(new_mem.memory == mem.memory);
+        assert!(new_mem.permissions == mem.permissions);
+    }
+
+    #[test]
+    fn read_write_bits() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(16).unwrap();
+        mem.write(base, &[0b1010_1100, 0b0000_0011, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+
+        // Bitfields straddling a byte boundary
+        assert_eq!(mem.read_bits(base, 6, 4, Endian::Little), Ok(0b1110));
+        assert_eq!(mem.read_bits(base, 4, 6, Endian::Big), Ok(0b110000));
+        assert_eq!(mem.read_bits(base, 8, 2, Endian::Little), Ok(0b11));
+
+        // Inserting a bitfield leaves the surrounding bits intact
+        mem.write_bits(base, 6, 4, Endian::Little, 0b0101).unwrap();
+        assert_eq!(mem.read_bits(base, 0, 16, Endian::Little),
+                   Ok(0b0000_0001_0110_1100));
+        mem.write_bits(base, 4, 6, Endian::Big, 0b011011).unwrap();
+        assert_eq!(mem.read_bits(base, 0, 16, Endian::Big),
+                   Ok(0b0110_0110_1100_0001));
+
+        // Full 64-bit fields spanning 9 bytes
+        for endian in [Endian::Little, Endian::Big] {
+            mem.write_bits(base, 3, 64, endian, 0xdead_beef_cafe_babe)
+                .unwrap();
+            assert_eq!(mem.read_bits(base, 3, 64, endian),
+                       Ok(0xdead_beef_cafe_babe));
+        }
+
+        // Zero and overly long bitfields are rejected
+        assert!(mem.read_bits(base, 0, 0, Endian::Little).is_err());
+        assert!(mem.read_bits(base, 0, 65, Endian::Little).is_err());
+
+        // Permissions are honored
+        assert!(!CHECKED ||
+                mem.read_bits(base, 70, 4, Endian::Little).is_err());
+    }
+
+    #[test]
+    fn deny_exec_by_default() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(64).unwrap();
+        mem.write(base, MSG).unwrap();
+        mem.set_deny_exec(true);
+
+        // Asking for `PERM_EXEC` through the normal path doesn't grant it
+        let mut buf = [0; 4];
+        mem.set_permissions(base, 64, Perm(PERM_READ | PERM_EXEC)).unwrap();
+        assert!(mem.fetch(base, &mut buf).is_err());
+        assert!(mem.read(base, &mut buf).is_ok());
+
+        // Explicitly finalizing the code does
+        mem.finalize_code(base, 64).unwrap();
+        assert!(mem.fetch(base, &mut buf).is_ok());
+        assert_eq!(&buf, &MSG[..4]);
+        assert!(!CHECKED || mem.write(base, b"asdf").is_err());
+    }
+
+    #[test]
+    fn debug_full_verify_passes() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let base = mem.allocate(DIRTY_BLOCK_SIZE * 2).unwrap();
+        mem.write(base, MSG).unwrap();
+
+        let mut new_mem = mem.fork();
+        new_mem.set_debug_full_verify(true);
+        new_mem.write(base, b"asdf").unwrap();
+        new_mem.write(VAddr(base.0 + DIRTY_BLOCK_SIZE), MSG).unwrap();
+        new_mem.reset(&mem);
+
+        // Permission changes dirty the blocks they touch as well
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let region = mem.allocate(DIRTY_BLOCK_SIZE).unwrap();
+        let mut new_mem = mem.fork();
+        new_mem.set_debug_full_verify(true);
+        new_mem.allocate(16).unwrap();
+        new_mem.append_guarded(region, DIRTY_BLOCK_SIZE - 8, b"asdf", 16)
+            .unwrap();
+        assert_eq!(new_mem.dirty_indexes, vec![1, 0]);
+        new_mem.reset(&mem);
+    }
+
+    #[test]
+    #[should_panic]
+    fn debug_full_verify_catches_untracked_writes() {
+        let mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let mut new_mem = mem.fork();
+        new_mem.set_debug_full_verify(true);
+
+        // Modify the memory behind the dirty tracking's back
+        new_mem.memory[DIRTY_BLOCK_SIZE + 1] = 0x41;
+        new_mem.reset(&mem);
+    }
+
+    #[test]
+    fn minidump_round_trip() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let data = mem.allocate(64).unwrap();
+        let rodata = mem.allocate(64).unwrap();
+        let text = mem.allocate(DIRTY_BLOCK_SIZE).unwrap();
+        mem.write(data, &[0x41; 64]).unwrap();
+        mem.write(rodata, MSG).unwrap();
+        mem.set_permissions(rodata, 64, Perm(PERM_READ)).unwrap();
+        mem.write(text, b"\x90\x90\xc3").unwrap();
+        mem.finalize_code(text, DIRTY_BLOCK_SIZE).unwrap();
+
+        let mut dump = Vec::new();
+        mem.write_minidump(&mut dump).unwrap();
+        let loaded = Mmu::read_minidump(&mut dump.as_slice()).unwrap();
+
+        // The mapped regions survive with their permissions and contents
+        let regions = mem.permission_regions();
+        assert_eq!(regions.len(), 3);
+        assert!(loaded.permission_regions() == regions);
+        for (base, size, _) in regions {
+            assert!(loaded.memory[base.0..base.0 + size] ==
+                    mem.memory[base.0..base.0 + size]);
+        }
+
+        // Garbage isn't a minidump
+        assert!(Mmu::read_minidump(&mut &b"asdfasdf"[..]).is_err());
+        assert!(Mmu::read_minidump(&mut &dump[..40]).is_err());
+
+        // Malformed memory info lists are rejected rather than walked
+        let info = MINIDUMP_HEADER_SIZE + 2 * 12;
+        let mut empty = dump.clone();
+        empty[info + 4..info + 8].copy_from_slice(&0u32.to_le_bytes());
+        empty[info + 8..info + 16].copy_from_slice(&(u32::MAX as u64)
+                                                   .to_le_bytes());
+        assert!(Mmu::read_minidump(&mut empty.as_slice()).is_err());
+        let mut huge = dump.clone();
+        huge[info + 8..info + 16].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Mmu::read_minidump(&mut huge.as_slice()).is_err());
+
+        // So are ranges too high up in memory to allocate
+        let ranges = info + 16 + 3 * MEMORY_INFO_SIZE + 16;
+        assert_eq!(dump[ranges..ranges + 8],
+            (mem.permission_regions()[0].0.0 as u64).to_le_bytes());
+        for base in [u64::MAX - 8, 0x7ff6_0000_0000] {
+            let mut high = dump.clone();
+            high[ranges..ranges + 8].copy_from_slice(&base.to_le_bytes());
+            let err = Mmu::read_minidump(&mut high.as_slice()).err();
+            assert_eq!(err.map(|err| err.kind()),
+                Some(io::ErrorKind::InvalidData));
+        }
+    }
+
+    #[test]
+    fn check_leaks() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        mem.allocate(16).unwrap();
+
+        // Allocations made before the fork don't count
+        let mut new_mem = mem.fork();
+        assert!(new_mem.check_leaks().is_empty());
+
+        let a = new_mem.allocate(32).unwrap();
+        let b = new_mem.allocate(7).unwrap();
+        assert!(new_mem.check_leaks() == [(a, 32), (b, 7)]);
+
+        // Resetting starts a new run
+        new_mem.reset(&mem);
+        assert!(new_mem.check_leaks().is_empty());
+    }
+
+    #[test]
+    fn iter_pod() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(64).unwrap();
+
+        // Write an unaligned guest array of `u32`s
+        let array = VAddr(base.0 + 1);
+        let bytes: Vec<u8> = (1..=10u32).flat_map(u32::to_ne_bytes).collect();
+        mem.write(array, &bytes).unwrap();
+
+        let sum: u32 = mem.iter_pod::<u32>(array, 10).unwrap().sum();
+        assert_eq!(sum, 55);
+
+        // Reaching into unreadable memory or past the end fails
+        assert!(mem.iter_pod::<u32>(array, 11).is_err());
+        assert!(mem.iter_pod::<u64>(VAddr(DIRTY_BLOCK_SIZE - 8), 2).is_err());
+        assert!(mem.iter_pod::<u32>(array, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn permission_enforcement_toggle() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(64).unwrap();
+        mem.set_permissions(base, 64, Perm(PERM_READ)).unwrap();
+        let mut new_mem = mem.fork();
+
+        assert!(!CHECKED || new_mem.write(base, MSG).is_err());
+
+        // Without enforcement the write goes through and is tracked as dirty
+        new_mem.set_enforce_permissions(false);
+        new_mem.write(base, MSG).unwrap();
+        assert!(new_mem.dirty_indexes == [0]);
+        let mut buf = [0; 4];
+        new_mem.fetch(base, &mut buf).unwrap();
+
+        // Bounds are still checked
+        assert!(new_mem.write(VAddr(DIRTY_BLOCK_SIZE - 1), b"as").is_err());
+
+        new_mem.set_enforce_permissions(true);
+        assert!(!CHECKED || new_mem.write(base, MSG).is_err());
+    }
+
+    #[test]
+    fn reset_permissions_only() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(64).unwrap();
+        let mut new_mem = mem.fork();
+        new_mem.write(base, MSG).unwrap();
+
+        new_mem.reset_permissions_only(&mem);
+        assert!(new_mem.dirty_indexes.is_empty());
+        assert!(new_mem.dirty_bitmap.iter().all(|&x| x == 0));
+
+        // The permissions are reverted but the bytes stay modified
+        assert!(new_mem.permissions == mem.permissions);
+        let mut buf = [0; MSG.len()];
+        assert!(!CHECKED || new_mem.read(base, &mut buf).is_err());
+        assert!(new_mem.memory[..MSG.len()] == *MSG);
+    }
+
+    #[test]
+    fn largest_free_bump() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        assert_eq!(mem.largest_free(), DIRTY_BLOCK_SIZE);
+
+        mem.allocate(1).unwrap();
+        mem.allocate(100).unwrap();
+        assert_eq!(mem.free_space(), DIRTY_BLOCK_SIZE - 16 - 112);
+        assert_eq!(mem.largest_free(), mem.free_space());
+
+        // Guard gaps, alignment padding and the null guard aren't free
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        mem.set_null_guard(64).unwrap();
+        mem.set_alloc_guard(2048);
+        mem.allocate(16).unwrap();
+        assert_eq!(mem.largest_free(), mem.free_space());
+        mem.allocate_aligned(16, DIRTY_BLOCK_SIZE).unwrap();
+        assert_eq!(mem.largest_free(), mem.free_space());
+        let grown = mem.allocate(16).unwrap();
+        mem.mremap(grown, 16, 512, false).unwrap();
+        assert_eq!(mem.largest_free(), mem.free_space());
+    }
+
+    #[test]
+    fn render_map_with_labels() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let heap = mem.allocate(64).unwrap();
+        let text = mem.allocate(32).unwrap();
+        mem.write(heap, &[0; 64]).unwrap();
+        mem.finalize_code(text, 32).unwrap();
+        mem.label_region(heap, 64, "heap");
+        mem.label_region(text, 32, ".text");
+
+        let expected = "0x00000000-0x00000040 rw- heap\n\
+                        0x00000040-0x00000060 r-x .text\n";
+        assert_eq!(mem.render_map(), expected);
+
+        // Labels survive forks and resets
+        let mut new_mem = mem.fork();
+        new_mem.write(heap, b"asdf").unwrap();
+        new_mem.reset(&mem);
+        assert_eq!(new_mem.render_map(), expected);
+    }
+
+    #[test]
+    fn exec_block() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let text = mem.allocate(DIRTY_BLOCK_SIZE).unwrap();
+        let data = mem.allocate(DIRTY_BLOCK_SIZE).unwrap();
+        mem.write(VAddr(text.0 + 16), b"\x90\xc3").unwrap();
+        mem.finalize_code(text, DIRTY_BLOCK_SIZE).unwrap();
+
+        let (base, code) = mem.exec_block(VAddr(text.0 + 17)).unwrap();
+        assert_eq!(base, text);
+        assert_eq!(code.len(), DIRTY_BLOCK_SIZE);
+        assert_eq!(&code[16..18], b"\x90\xc3");
+
+        // Partially or non-executable blocks are rejected
+        mem.finalize_code(data, 16).unwrap();
+        assert!(mem.exec_block(data).is_none());
+        assert!(mem.exec_block(VAddr(DIRTY_BLOCK_SIZE * 2)).is_none());
+    }
+
+    #[test]
+    fn append_guarded() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let region = mem.allocate(256).unwrap();
+
+        let len = mem.append_guarded(region, 0, b"asdf", 16).unwrap();
+        assert_eq!(len, 4);
+        assert!(!CHECKED || mem.write(VAddr(region.0 + len), b"a").is_err());
+
+        // The guard follows the end of the buffer
+        let len = mem.append_guarded(region, len, MSG, 16).unwrap();
+        assert_eq!(len, 4 + MSG.len());
+        assert!(!CHECKED || mem.write(VAddr(region.0 + len), b"a").is_err());
+        assert!(!CHECKED ||
+                mem.write(VAddr(region.0 + len + 15), b"a").is_err());
+        assert!(mem.write(VAddr(region.0 + len + 16), b"a").is_ok());
+
+        let mut buf = [0; 4 + MSG.len()];
+        mem.read(region, &mut buf).unwrap();
+        assert_eq!(&buf[..4], b"asdf");
+        assert!(buf[4..] == *MSG);
+
+        // Appending until the guard doesn't fit fails
+        assert!(mem.append_guarded(region, DIRTY_BLOCK_SIZE - 8, b"asdf", 16)
+            .is_err());
+    }
+
+    #[test]
+    fn sample_dirty() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        let base = mem.allocate(DIRTY_BLOCK_SIZE * 4).unwrap();
+        let mut new_mem = mem.fork();
+        assert_eq!(new_mem.sample_dirty(), [false; 4]);
+
+        new_mem.write(VAddr(base.0 + 8), b"asdf").unwrap();
+        new_mem.write(VAddr(base.0 + DIRTY_BLOCK_SIZE * 2), b"asdf").unwrap();
+        assert_eq!(new_mem.sample_dirty(), [true, false, true, false]);
+    }
+
+    #[test]
+    fn grow_with_perm() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        mem.grow(DIRTY_BLOCK_SIZE).unwrap();
+        assert_eq!(mem.memory.len(), DIRTY_BLOCK_SIZE * 2);
+        assert!(!CHECKED ||
+                mem.write(VAddr(DIRTY_BLOCK_SIZE), b"asdf").is_err());
+
+        // The grown tail is writable right away
+        mem.grow_with_perm(100, Perm(PERM_RAW | PERM_WRITE)).unwrap();
+        assert_eq!(mem.memory.len(), DIRTY_BLOCK_SIZE * 2 + 112);
+        let tail = VAddr(DIRTY_BLOCK_SIZE * 2);
+        assert!(mem.write(tail, MSG).is_ok());
+        let mut buf = [0; MSG.len()];
+        mem.read(tail, &mut buf).unwrap();
+        assert!(buf == *MSG);
+
+        // The bump allocator can use the grown memory
+        assert!(mem.allocate(DIRTY_BLOCK_SIZE * 2).is_ok());
+    }
+
+    #[test]
+    fn reset_grown_fork() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE + 0x10);
+        let base = mem.allocate(0x10).unwrap();
+        let baseline = Arc::new(mem.fork());
+
+        // Forks grown past the baseline reset the grown tail to zero
+        for mut new_mem in [mem.fork(), Mmu::fork_cow(&baseline)] {
+            new_mem.set_debug_full_verify(true);
+            new_mem.grow_with_perm(DIRTY_BLOCK_SIZE,
+                                   Perm(PERM_READ | PERM_WRITE)).unwrap();
+            let tail = VAddr(DIRTY_BLOCK_SIZE * 2);
+            new_mem.write(base, b"asdf").unwrap();
+            new_mem.write(tail, b"asdf").unwrap();
+            new_mem.reset(&baseline);
+
+            assert!(new_mem.first_divergence(&baseline).is_none());
+            let (from, to) = (tail.0, tail.0 + 4);
+            assert!(new_mem.bytes(from, to) == [0; 4].as_slice());
+            assert!(new_mem.perms(from, to) == [Perm(0); 4].as_slice());
+        }
+    }
+
+    #[test]
+    fn copy_region_between_mmus() {
+        let mut template = Mmu::new(DIRTY_BLOCK_SIZE);
+        let dict = template.allocate(MSG.len()).unwrap();
+        template.write(dict, MSG).unwrap();
+        template.set_permissions(dict, MSG.len(), Perm(0)).unwrap();
+
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let base = mem.allocate(DIRTY_BLOCK_SIZE * 2).unwrap();
+        let dst = VAddr(base.0 + DIRTY_BLOCK_SIZE);
+        let mut new_mem = mem.fork();
+        new_mem.copy_region_from(&template, dict, dst, MSG.len()).unwrap();
+
+        let mut buf = [0; MSG.len()];
+        new_mem.read(dst, &mut buf).unwrap();
+        assert!(buf == *MSG);
+        assert!(new_mem.dirty_indexes == [1]);
+
+        // The destination bounds are still checked
+        assert!(new_mem.copy_region_from(&template, dict,
+            VAddr(DIRTY_BLOCK_SIZE * 2 - 4), MSG.len()).is_err());
+        assert!(new_mem.copy_region_from(&template,
+            VAddr(DIRTY_BLOCK_SIZE - 4), dst, MSG.len()).is_err());
+    }
+
+    #[test]
+    fn rollback_to_named_checkpoint() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let base = mem.allocate(DIRTY_BLOCK_SIZE).unwrap();
+        mem.write(base, MSG).unwrap();
+
+        mem.checkpoint("after_setup");
+        mem.write(base, b"asdf").unwrap();
+        let scratch = mem.allocate(64).unwrap();
+
+        mem.checkpoint("pre_parse");
+        mem.write(base, b"qwer").unwrap();
+        mem.write(scratch, b"zxcv").unwrap();
+        mem.set_permissions(base, 4, Perm(0)).unwrap();
+
+        // Rolling back to the earlier checkpoint undoes everything after it
+        mem.rollback("after_setup").unwrap();
+        let mut buf = [0; MSG.len()];
+        mem.read(base, &mut buf).unwrap();
+        assert!(buf == *MSG);
+        assert!(mem.alloc_base == scratch);
+        assert!(mem.permissions[scratch.0..scratch.0 + 64].iter()
+            .all(|x| x.0 == 0));
+
+        // Later checkpoints are gone, the rolled back to one isn't
+        assert_eq!(mem.rollback("pre_parse"), Err(MmuError::InvalidArgument));
+        mem.write(base, b"asdf").unwrap();
+        mem.rollback("after_setup").unwrap();
+        mem.read(base, &mut buf).unwrap();
+        assert!(buf == *MSG);
+    }
+
+    #[test]
+    fn wx_policy() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let text = mem.allocate(64).unwrap();
+        let data = mem.allocate(64).unwrap();
+        mem.finalize_code(text, 64).unwrap();
+        mem.write(data, MSG).unwrap();
+
+        assert!(mem.assert_wx_policy(WxPolicy::Exclusive).is_ok());
+        assert!(mem.assert_wx_policy(WxPolicy::NoExec) ==
+                Err(vec![(text, 64)]));
+
+        // A writable and executable region violates W^X
+        let jit = VAddr(data.0 + 16);
+        mem.set_permissions(jit, 32, Perm(PERM_READ | PERM_WRITE | PERM_EXEC))
+            .unwrap();
+        mem.set_permissions(VAddr(jit.0 + 8), 8, Perm(PERM_WRITE | PERM_EXEC))
+            .unwrap();
+        assert!(mem.assert_wx_policy(WxPolicy::Exclusive) ==
+                Err(vec![(jit, 32)]));
+    }
+
+    #[test]
+    fn reset_self_with_attached_baseline() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(MSG.len()).unwrap();
+        mem.write(base, MSG).unwrap();
+        let mem = Arc::new(mem);
+
+        let mut new_mem = mem.fork();
+        assert_eq!(new_mem.reset_self(), Err(MmuError::NoBaseline));
+        new_mem.attach_baseline(mem.clone());
+
+        new_mem.write(base, b"asdf").unwrap();
+        new_mem.reset_self().unwrap();
+        let mut buf = [0; MSG.len()];
+        new_mem.read(base, &mut buf).unwrap();
+        assert!(buf == *MSG);
+        assert!(new_mem.dirty_indexes.is_empty());
+    }
+
+    #[test]
+    fn permission_violation_mask() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(8).unwrap();
+        mem.write(base, &[0; 8]).unwrap();
+
+        // Punch some holes into the region
+        mem.set_permissions(VAddr(base.0 + 1), 1, Perm(PERM_WRITE)).unwrap();
+        mem.set_permissions(VAddr(base.0 + 4), 2, Perm(0)).unwrap();
+        mem.set_permissions(VAddr(base.0 + 7), 1, Perm(PERM_READ)).unwrap();
+
+        let mask = mem.permission_violation_mask(base, 8,
+            Perm(PERM_READ | PERM_WRITE)).unwrap();
+        assert_eq!(mask, [false, true, false, false, true, true, false, true]);
+        let mask = mem.permission_violation_mask(base, 8, Perm(PERM_READ))
+            .unwrap();
+        assert_eq!(mask, [false, true, false, false, true, true, false, false]);
+
+        assert!(mem.permission_violation_mask(VAddr(DIRTY_BLOCK_SIZE - 4), 8,
+            Perm(PERM_READ)).is_none());
+    }
+
+    #[test]
+    fn mremap_grow_in_place() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(16).unwrap();
+        mem.write(base, b"asdf").unwrap();
+
+        // The last allocation can simply grow
+        assert_eq!(mem.mremap(base, 16, 100, false), Ok(base));
+        assert!(mem.write(VAddr(base.0 + 99), b"a").is_ok());
+        assert!(!CHECKED || mem.write(VAddr(base.0 + 100), b"a").is_err());
+        assert_eq!(mem.allocate(1), Ok(VAddr(base.0 + 112)));
+        assert_eq!(mem.total_allocated(), 101);
+
+        // Not past the end of memory though
+        assert!(mem.mremap(VAddr(base.0 + 112), 1, DIRTY_BLOCK_SIZE, false)
+            .is_err());
+    }
+
+    #[test]
+    fn mremap_grow_relocating() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let a = mem.allocate(MSG.len()).unwrap();
+        let b = mem.allocate(16).unwrap();
+        mem.write(a, MSG).unwrap();
+
+        // Something follows `a`, so it can only grow by moving
+        assert!(mem.mremap(a, MSG.len(), 128, false).is_err());
+        let new = mem.mremap(a, MSG.len(), 128, true).unwrap();
+        assert!(new > b);
+
+        let mut buf = [0; MSG.len()];
+        mem.read(new, &mut buf).unwrap();
+        assert!(buf == *MSG);
+        assert!(mem.write(VAddr(new.0 + 127), b"a").is_ok());
+
+        // The old mapping is gone
+        assert!(!CHECKED || mem.read(a, &mut buf).is_err());
+        assert!(mem.mremap(a, MSG.len(), 16, true).is_err());
+        assert_eq!(mem.total_allocated(), 16 + 128);
+    }
+
+    #[test]
+    fn mremap_shrink() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(64).unwrap();
+        mem.write(base, &[0x41; 64]).unwrap();
+
+        assert_eq!(mem.mremap(base, 64, 16, false), Ok(base));
+        let mut buf = [0; 16];
+        mem.read(base, &mut buf).unwrap();
+        assert!(!CHECKED || mem.write(VAddr(base.0 + 16), b"a").is_err());
+        assert_eq!(mem.total_allocated(), 16);
+
+        // The old length no longer matches
+        assert!(mem.mremap(base, 64, 128, true).is_err());
+    }
+
+    #[test]
+    fn mremap_reset_restores_permissions() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let a = mem.allocate(64).unwrap();
+        mem.allocate(16).unwrap();
+        let base = mem.fork();
+
+        // Shrinking unmaps the tail until the reset
+        let mut fork = base.fork();
+        fork.mremap(a, 64, 16, false).unwrap();
+        fork.reset(&base);
+        assert!(fork.first_divergence(&base).is_none());
+
+        // Moving unmaps the old allocation until the reset
+        let mut fork = base.fork();
+        fork.mremap(a, 64, 128, true).unwrap();
+        fork.reset(&base);
+        assert!(fork.permissions[a.0..a.0 + 64] ==
+                base.permissions[a.0..a.0 + 64]);
+    }
+
+    #[test]
+    fn default_alloc_perm() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        mem.set_default_alloc_perm(Perm(PERM_READ));
+
+        let base = mem.allocate(16).unwrap();
+        assert!((0..16).all(|ii| mem.permissions[base.0 + ii].0 == PERM_READ));
+        let mut buf = [0; 16];
+        assert!(mem.read(base, &mut buf).is_ok());
+        assert!(!CHECKED || mem.write(base, b"a").is_err());
+
+        // An explicit permission still wins
+        let base = mem.allocate_perm(16, Perm(PERM_WRITE)).unwrap();
+        assert!(mem.write(base, b"a").is_ok());
+    }
+
+    #[test]
+    fn native_endian_helpers() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate_perm(16, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
+
+        mem.write_u32_ne(base, 0xdeadbeef).unwrap();
+        assert_eq!(mem.read_u32(base, Endian::NATIVE), Ok(0xdeadbeef));
+        mem.write_u32(base, Endian::NATIVE, 0x41424344).unwrap();
+        assert_eq!(mem.read_u32_ne(base), Ok(0x41424344));
+
+        let addr = VAddr(base.0 + 8);
+        mem.write_u64_ne(addr, 0x0123456789abcdef).unwrap();
+        assert_eq!(mem.read_u64(addr, Endian::NATIVE),
+                   Ok(0x0123456789abcdef));
+        mem.write_u64(addr, Endian::NATIVE, 0xfedcba9876543210).unwrap();
+        assert_eq!(mem.read_u64_ne(addr), Ok(0xfedcba9876543210));
+
+        // Same bytes as the host's own encoding
+        let mut buf = [0u8; 8];
+        mem.read(addr, &mut buf).unwrap();
+        assert_eq!(buf, 0xfedcba9876543210u64.to_ne_bytes());
+
+        // Explicit orders still apply
+        mem.write_u32(base, Endian::Big, 0x41424344).unwrap();
+        let mut buf = [0u8; 4];
+        mem.read(base, &mut buf).unwrap();
+        assert_eq!(&buf, b"ABCD");
+        assert_eq!(mem.read_u32(base, Endian::Little), Ok(0x44434241));
+        assert!(!CHECKED || mem.read_u64_ne(VAddr(base.0 + 12)).is_err());
+    }
+
+    #[test]
+    fn follow_chain() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        mem.set_default_alloc_perm(Perm(PERM_READ | PERM_WRITE));
+
+        // Keep NULL out of the list
+        mem.allocate(16).unwrap();
+
+        // Nodes are `{ value: u64, next: u64 }`
+        let nodes: Vec<VAddr> = (0..4).map(|_| mem.allocate(16).unwrap())
+            .collect();
+        for pair in nodes.windows(2) {
+            mem.write_u64(VAddr(pair[0].0 + 8), Endian::Little,
+                          pair[1].0 as u64).unwrap();
+        }
+
+        // NULL-terminated
+        assert_eq!(mem.follow_chain(nodes[0], 8, Endian::Little, 16),
+                   Ok(nodes.clone()));
+        assert_eq!(mem.follow_chain(VAddr(0), 8, Endian::Little, 16),
+                   Ok(Vec::new()));
+
+        // Cut short at `max` nodes
+        assert_eq!(mem.follow_chain(nodes[0], 8, Endian::Little, 2),
+                   Ok(nodes[..2].to_vec()));
+
+        // Pointing at unmapped memory
+        mem.write_u64(VAddr(nodes[3].0 + 8), Endian::Little, 0x800).unwrap();
+        assert_eq!(mem.follow_chain(nodes[0], 8, Endian::Little, 16),
+                   Err(MmuError::PermissionDenied {
+                       addr:    VAddr(0x800),
+                       missing: Perm(PERM_READ),
+                   }));
+
+        // Looping back onto itself
+        mem.write_u64(VAddr(nodes[3].0 + 8), Endian::Little,
+                      nodes[1].0 as u64).unwrap();
+        assert_eq!(mem.follow_chain(nodes[0], 8, Endian::Little, 16),
+                   Err(MmuError::Cycle { addr: nodes[1] }));
+        assert_eq!(mem.follow_chain(nodes[0], 8, Endian::Little, 4),
+                   Ok(nodes.clone()));
+    }
+
+    #[test]
+    fn permission_delta() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE);
+        let a = base.allocate(32).unwrap();
+        let b = base.allocate(32).unwrap();
+        let mut fork = base.fork();
+        assert!(fork.permission_delta(&base).is_empty());
+
+        fork.set_permissions(VAddr(a.0 + 8), 8, Perm(PERM_READ)).unwrap();
+        fork.set_permissions(b, 16, Perm(PERM_READ)).unwrap();
+        fork.set_permissions(VAddr(b.0 + 16), 8, Perm(0)).unwrap();
+        assert_eq!(fork.permission_delta(&base), vec![
+            (VAddr(a.0 + 8),  8,  Perm(PERM_READ)),
+            (b,               16, Perm(PERM_READ)),
+            (VAddr(b.0 + 16), 8,  Perm(0)),
+        ]);
+
+        // Setting the original permission back drops it from the delta
+        fork.set_permissions(VAddr(a.0 + 8), 8, Perm(PERM_RAW | PERM_WRITE))
+            .unwrap();
+        assert_eq!(fork.permission_delta(&base).len(), 2);
+    }
+
+    #[test]
+    fn lock_region() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        base.set_default_alloc_perm(Perm(PERM_READ | PERM_WRITE));
+        let dict = base.allocate(DIRTY_BLOCK_SIZE * 2).unwrap();
+        let mut fork = base.fork();
+
+        // Locking in the fork makes its current contents stick
+        fork.write(dict, b"asdf").unwrap();
+        fork.lock_region(dict, DIRTY_BLOCK_SIZE).unwrap();
+        assert!(fork.write(dict, b"a").is_err());
+        assert!(fork.write_unchecked_perm(dict, b"a").is_err());
+        assert!(fork.write(VAddr(dict.0 + DIRTY_BLOCK_SIZE), b"a").is_ok());
+        assert_eq!(fork.dirty_indexes, vec![1]);
+
+        fork.reset(&base);
+        let mut buf = [0; 4];
+        fork.read(dict, &mut buf).unwrap();
+        assert_eq!(&buf, b"asdf");
+        assert!(fork.write(dict, b"a").is_err());
+        assert!(fork.dirty_indexes.is_empty());
+
+        // Partially covered blocks only lose `PERM_WRITE`
+        let end = VAddr(dict.0 + DIRTY_BLOCK_SIZE * 2 - 1);
+        fork.lock_region(VAddr(end.0 - 8), 8).unwrap();
+        assert!(!CHECKED || fork.write(VAddr(end.0 - 8), b"a").is_err());
+        assert!(fork.write_unchecked_perm(VAddr(end.0 - 8), b"a").is_ok());
+        assert!(fork.lock_region(end, DIRTY_BLOCK_SIZE * 4).is_err());
+    }
+
+    #[test]
+    fn dirty_cache_lines() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE);
+        let addr = base.allocate(DIRTY_BLOCK_SIZE).unwrap();
+        let mut mem = base.fork();
+        assert!(mem.dirty_cache_lines().is_empty());
+
+        mem.track_cache_lines(Some(64));
+        mem.write(VAddr(addr.0 + 8), b"a").unwrap();
+        mem.write(VAddr(addr.0 + 60), b"asdfasdf").unwrap();
+        mem.write(VAddr(addr.0 + 1000), b"a").unwrap();
+        assert_eq!(mem.dirty_cache_lines(),
+                   vec![VAddr(0), VAddr(64), VAddr(960)]);
+
+        // The reset still works with the whole block
+        assert_eq!(mem.dirty_indexes, vec![0]);
+        mem.reset(&base);
+        assert!(mem.dirty_cache_lines().is_empty());
+        assert!(mem.first_divergence(&base).is_none());
+    }
+
+    #[test]
+    fn pagemap() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        mem.set_permissions(VAddr(8), 8, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
+        mem.set_permissions(VAddr(DIRTY_BLOCK_SIZE * 2), DIRTY_BLOCK_SIZE,
+                            Perm(PERM_READ | PERM_EXEC)).unwrap();
+        mem.set_permissions(VAddr(DIRTY_BLOCK_SIZE * 3 + 1), 1,
+                            Perm(PERM_WRITE)).unwrap();
+
+        assert_eq!(mem.pagemap(), vec![
+            PAGEMAP_PRESENT | PAGEMAP_READ | PAGEMAP_WRITE,
+            0,
+            PAGEMAP_PRESENT | PAGEMAP_READ | PAGEMAP_EXEC,
+            PAGEMAP_PRESENT | PAGEMAP_WRITE,
+        ]);
+    }
+
+    #[test]
+    fn replay_up_to() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE);
+        let addr = base.allocate(16).unwrap();
+        let mut mem = base.fork();
+
+        let ((), journal) = mem.journal_writes(|mem| {
+            mem.write(addr, b"aaaa").unwrap();
+            let ((), inner) = mem.journal_writes(|mem| {
+                mem.write(VAddr(addr.0 + 2), b"bb").unwrap();
+            });
+            assert_eq!(inner.writes, vec![(VAddr(addr.0 + 2), b"bb".to_vec())]);
+            mem.write(VAddr(addr.0 + 3), b"cc").unwrap();
+        });
+        assert_eq!(journal.writes.len(), 3);
+
+        let state = |index| {
+            let mut fork = base.fork();
+            fork.replay_up_to(&journal, index).unwrap();
+            let mut buf = [0; 5];
+            fork.read_lenient(addr, &mut buf, b'.');
+            buf
+        };
+        assert_eq!(&state(0), b".....");
+        assert_eq!(&state(1), b"aaaa.");
+        assert_eq!(&state(2), b"aabb.");
+        assert_eq!(&state(3), b"aabcc");
+        assert_eq!(base.fork().replay_up_to(&journal, 4),
+                   Err(MmuError::InvalidArgument));
+    }
+
+    #[test]
+    fn check_access_combined() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate_perm(32, Perm(PERM_READ | PERM_EXEC)).unwrap();
+        let addr = VAddr(base.0 + 16);
+        mem.set_permissions(addr, 16, Perm(PERM_READ)).unwrap();
+
+        let rx = Perm(PERM_READ | PERM_EXEC);
+        assert_eq!(mem.check_access(base, 16, rx), Ok(()));
+        assert_eq!(mem.check_access(base, 32, rx),
+                   Err(MmuError::PermissionDenied {
+                       addr,
+                       missing: Perm(PERM_EXEC),
+                   }));
+        assert_eq!(mem.check_access(base, 32, Perm(PERM_WRITE | PERM_READ)),
+                   Err(MmuError::PermissionDenied {
+                       addr:    base,
+                       missing: Perm(PERM_WRITE),
+                   }));
+        assert!(mem.ensure_perms(base, 16, rx).is_ok());
+        assert!(mem.ensure_perms(addr, 1, rx).is_err());
+
+        // Out of bounds reports the whole range
+        assert_eq!(mem.check_access(VAddr(DIRTY_BLOCK_SIZE - 1), 2, rx),
+                   Err(MmuError::OutOfBounds {
+                       addr: VAddr(DIRTY_BLOCK_SIZE - 1),
+                       len:  2,
+                   }));
+    }
+
+    #[test]
+    fn map_segment_bss() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        mem.memory.fill(0x41);
+        let addr = VAddr(0x100);
+
+        mem.map_segment(addr, b"data", 16, Perm(PERM_READ)).unwrap();
+        let mut buf = [0; 16];
+        mem.read(addr, &mut buf).unwrap();
+        assert_eq!(&buf, b"data\0\0\0\0\0\0\0\0\0\0\0\0");
+        assert!((0..16).all(|ii| mem.permissions[addr.0 + ii].0 == PERM_READ));
+        assert_eq!(mem.permissions[addr.0 + 16].0, 0);
+        assert_eq!(mem.dirty_indexes, vec![0]);
+
+        // The file bytes have to fit in the segment, which has to fit in memory
+        assert!(mem.map_segment(addr, b"data", 3, Perm(PERM_READ)).is_err());
+        assert!(mem.map_segment(VAddr(DIRTY_BLOCK_SIZE - 8), b"", 16,
+                                Perm(PERM_READ)).is_err());
+
+        // A `.bss` reaching into a locked block fails before writing anything
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        mem.lock_region(VAddr(DIRTY_BLOCK_SIZE), DIRTY_BLOCK_SIZE).unwrap();
+        let addr = VAddr(DIRTY_BLOCK_SIZE - 8);
+        assert_eq!(mem.map_segment(addr, b"data", 16, Perm(PERM_READ)),
+                   Err(MmuError::Locked { addr }));
+        assert_eq!(&mem.memory[addr.0..addr.0 + 4], &[0; 4]);
+        assert!(mem.dirty_indexes.is_empty());
+    }
+
+    #[test]
+    fn write_reporting() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(16).unwrap();
+        let addr = VAddr(base.0 + 8);
+        mem.set_permissions(addr, 8, Perm(PERM_READ)).unwrap();
+
+        assert_eq!(mem.write_reporting(base, b"asdf"), Ok(4));
+
+        let (written, err) = mem.write_reporting(VAddr(base.0 + 4), MSG)
+            .unwrap_err();
+        assert_eq!(written, 4);
+        assert_eq!(err, MmuError::PermissionDenied {
+            addr,
+            missing: Perm(PERM_WRITE),
+        });
+
+        // Everything up to the fault was written
+        let mut buf = [0; 8];
+        mem.read(base, &mut buf).unwrap();
+        assert_eq!(&buf[..4], b"asdf");
+        assert_eq!(&buf[4..], &MSG[..4]);
+        assert_eq!(mem.memory[addr.0], 0);
+
+        // Nothing gets written when the first byte faults
+        let (written, err) = mem
+            .write_reporting(VAddr(DIRTY_BLOCK_SIZE), b"a").unwrap_err();
+        assert_eq!(written, 0);
+        assert_eq!(err, MmuError::OutOfBounds {
+            addr: VAddr(DIRTY_BLOCK_SIZE),
+            len:  1,
+        });
+
+        // Locked blocks stop the write even if they're writable again
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let base = mem.allocate(DIRTY_BLOCK_SIZE * 2).unwrap();
+        let locked = VAddr(base.0 + DIRTY_BLOCK_SIZE);
+        mem.lock_region(locked, DIRTY_BLOCK_SIZE).unwrap();
+        mem.set_permissions(locked, 8, Perm(PERM_WRITE)).unwrap();
+        let (written, err) = mem
+            .write_reporting(VAddr(locked.0 - 4), b"asdfasdf").unwrap_err();
+        assert_eq!(written, 4);
+        assert_eq!(err, MmuError::Locked { addr: locked });
+        assert_eq!(&mem.memory[locked.0 - 4..locked.0 + 4], b"asdf\0\0\0\0");
+    }
+
+    #[test]
+    fn set_persistent() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE * 3);
+        base.set_default_alloc_perm(Perm(PERM_READ | PERM_WRITE));
+        let addr = base.allocate(DIRTY_BLOCK_SIZE * 3).unwrap();
+        let cov  = VAddr(addr.0 + DIRTY_BLOCK_SIZE);
+        let mut mem = base.fork();
+        mem.set_debug_full_verify(true);
+
+        mem.write(addr, b"a").unwrap();
+        mem.write(cov, b"cov").unwrap();
+        mem.set_persistent(cov, 3, true).unwrap();
+        mem.reset(&base);
+
+        // Only the persistent block survived
+        let mut buf = [0; 3];
+        mem.read(cov, &mut buf).unwrap();
+        assert_eq!(&buf, b"cov");
+        assert_eq!(mem.memory[addr.0], 0);
+        assert!(mem.dirty_indexes.is_empty());
+
+        // Once volatile again, it's restored as usual
+        mem.write(cov, b"new").unwrap();
+        mem.set_persistent(cov, 3, false).unwrap();
+        mem.reset(&base);
+        assert!(mem.first_divergence(&base).is_none());
+        assert!(mem.set_persistent(VAddr(DIRTY_BLOCK_SIZE * 3), 1, true)
+            .is_err());
+    }
+
+    #[test]
+    fn verify_journal() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE);
+        let addr = base.allocate(32).unwrap();
+        let mut mem = base.fork();
+
+        let ((), mut journal) = mem.journal_writes(|mem| {
+            mem.write(addr, b"asdf").unwrap();
+            mem.write(VAddr(addr.0 + 16), b"qwer").unwrap();
+        });
+        assert_eq!(mem.verify_journal(&base, &journal), Ok(()));
+
+        // Tampering with the data shows up where it was written
+        journal.writes[1].1[1] = b'x';
+        assert_eq!(mem.verify_journal(&base, &journal),
+                   Err((VAddr(addr.0 + 17), 1)));
+
+        // Dropping a write shows up as the whole write, with its permissions
+        journal.writes.remove(1);
+        assert_eq!(mem.verify_journal(&base, &journal),
+                   Err((VAddr(addr.0 + 16), 4)));
+
+        journal.writes.push((VAddr(DIRTY_BLOCK_SIZE - 1), b"aa".to_vec()));
+        assert_eq!(mem.verify_journal(&base, &journal),
+                   Err((VAddr(DIRTY_BLOCK_SIZE - 1), 2)));
+    }
 
     #[test]
-    fn read_write() {
+    fn working_set_blocks() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        let addr = base.allocate(DIRTY_BLOCK_SIZE * 4).unwrap();
+        let mut mem = base.fork();
+        assert_eq!(mem.working_set_blocks(), 0);
+
+        mem.write(addr, b"a").unwrap();
+        mem.write(VAddr(addr.0 + 8), b"a").unwrap();
+        mem.reset(&base);
+        mem.write(addr, b"a").unwrap();
+        mem.write(VAddr(addr.0 + DIRTY_BLOCK_SIZE * 2), b"a").unwrap();
+        mem.reset(&base);
+        mem.write(VAddr(addr.0 + DIRTY_BLOCK_SIZE * 3), b"a").unwrap();
+        assert_eq!(mem.working_set_blocks(), 3);
+
+        mem.clear_working_set();
+        assert_eq!(mem.working_set_blocks(), 0);
+        mem.write(addr, b"a").unwrap();
+        assert_eq!(mem.working_set_blocks(), 1);
+    }
+
+    #[test]
+    fn reset_multi_block_write() {
+        let base = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        let mut mem = base.fork();
+        let addr = mem.allocate(DIRTY_BLOCK_SIZE * 4).unwrap();
+        let from = VAddr(addr.0 + DIRTY_BLOCK_SIZE - 8);
+
+        // Spans blocks 0 through 3
+        mem.write(from, &vec![0x41; DIRTY_BLOCK_SIZE * 2 + 16]).unwrap();
+        let mut dirty = mem.dirty_indexes.clone();
+        dirty.sort_unstable();
+        assert_eq!(dirty, vec![0, 1, 2, 3]);
+        assert_eq!(mem.dirty_bitmap[0], 0b1111);
+
+        // Writes ending on a block boundary don't touch the next block
+        mem.write(addr, &[0x41; DIRTY_BLOCK_SIZE * 4]).unwrap();
+        assert_eq!(mem.dirty_indexes.len(), 4);
+
+        mem.reset(&base);
+        assert!(mem.memory.iter().all(|&x| x == 0));
+        assert!(mem.permissions.iter().all(|x| x.0 == 0));
+        assert!(mem.first_divergence(&base).is_none());
+    }
+
+    #[test]
+    fn freeze_and_fork_shared() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE);
+        let addr = base.allocate(16).unwrap();
+        base.write(addr, b"base").unwrap();
+        let base = base.freeze();
+
+        let workers: Vec<_> = (0..4u8).map(|ii| {
+            let base = Arc::clone(&base);
+            std::thread::spawn(move || {
+                let mut mem = Mmu::fork_shared(&base);
+                mem.write(addr, &[b'0' + ii; 4]).unwrap();
+                let mut buf = [0; 4];
+                mem.read(addr, &mut buf).unwrap();
+                assert_eq!(buf, [b'0' + ii; 4]);
+                assert_eq!(mem.cow_copied_blocks(), 1);
+
+                mem.reset_self().unwrap();
+                mem.read(addr, &mut buf).unwrap();
+                assert_eq!(&buf, b"base");
+                assert_eq!(mem.cow_copied_blocks(), 0);
+            })
+        }).collect();
+        workers.into_iter().for_each(|x| x.join().unwrap());
+
+        // None of the workers touched the baseline
+        let mut buf = [0; 4];
+        base.read(addr, &mut buf).unwrap();
+        assert_eq!(&buf, b"base");
+    }
+
+    #[test]
+    fn reset_partial_last_block() {
+        let base = Mmu::new(DIRTY_BLOCK_SIZE + 16);
+        let mut mem = base.fork();
+        let addr = mem.allocate(DIRTY_BLOCK_SIZE + 16).unwrap();
+
+        mem.write(VAddr(addr.0 + DIRTY_BLOCK_SIZE), &[0x41; 16]).unwrap();
+        mem.write(VAddr(addr.0 + DIRTY_BLOCK_SIZE - 4), &[0x41; 8]).unwrap();
+        mem.reset(&base);
+        assert!(mem.first_divergence(&base).is_none());
+        assert!(mem.dirty_indexes.is_empty());
+    }
+
+    #[test]
+    fn assert_layout() {
         let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
-        let mut buf = [0; MSG.len()];
-        mem.allocate(MSG.len()).unwrap();
-        mem.write(VAddr(0x0), MSG).unwrap();
-        mem.read(VAddr(0x0), &mut buf).unwrap();
-        assert!(buf[0..MSG.len()] == *MSG);
+        let text = mem.allocate_perm(32, Perm(PERM_READ | PERM_EXEC)).unwrap();
+        let data = mem.allocate_perm(32, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
+        mem.set_permissions(VAddr(data.0 + 8), 8, Perm(PERM_READ)).unwrap();
+
+        let mut layout = vec![
+            (text,              32, Perm(PERM_READ | PERM_EXEC)),
+            (data,              8,  Perm(PERM_READ | PERM_WRITE)),
+            (VAddr(data.0 + 8), 8,  Perm(PERM_READ)),
+        ];
+        assert_eq!(mem.assert_layout(&layout), Ok(()));
+
+        // The data segment doesn't have the same permissions throughout
+        layout[1].1 = 32;
+        assert_eq!(mem.assert_layout(&layout), Err(LayoutMismatch {
+            range:    (data, 32),
+            addr:     VAddr(data.0 + 8),
+            expected: Perm(PERM_READ | PERM_WRITE),
+            actual:   Perm(PERM_READ),
+        }));
     }
 
     #[test]
-    #[should_panic]
-    fn read_uninitialized_memory() {
-        let mem = Mmu::new(DIRTY_BLOCK_SIZE);
-        let mut buf = [0; MSG.len()];
-        mem.read(VAddr(0x0), &mut buf).unwrap();
+    fn reset_clears_only_dirty_bits() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        let addr = base.allocate(DIRTY_BLOCK_SIZE * 4).unwrap();
+        let mut mem = base.fork();
+
+        // Blocks 0 and 2 share a bitmap word without being adjacent
+        mem.write(addr, b"a").unwrap();
+        mem.write(VAddr(addr.0 + DIRTY_BLOCK_SIZE * 2), b"a").unwrap();
+        assert_eq!(mem.dirty_bitmap[0], 0b101);
+
+        mem.reset(&base);
+        assert!(mem.dirty_bitmap.iter().all(|&x| x == 0));
+        assert!(mem.dirty_indexes.is_empty());
+
+        // The bitmap is consistent again, so blocks get tracked once more
+        mem.write(VAddr(addr.0 + DIRTY_BLOCK_SIZE * 2), b"a").unwrap();
+        assert_eq!(mem.dirty_bitmap[0], 0b100);
+        assert_eq!(mem.dirty_indexes, vec![2]);
     }
 
     #[test]
-    #[should_panic]
-    fn write_unallocated_memory() {
+    fn read_after_write() {
         let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
-        mem.write(VAddr(0x0), MSG).unwrap();
+        let base = mem.allocate(16).unwrap();
+        assert_eq!(mem.permissions[base.0].0, PERM_RAW | PERM_WRITE);
+
+        // Freshly allocated memory can't be read
+        let mut buf = [0; 16];
+        assert!(!CHECKED || mem.read(base, &mut buf).is_err());
+
+        // Only the written sub-range becomes readable
+        mem.write(VAddr(base.0 + 4), b"asdf").unwrap();
+        assert_eq!(mem.permissions[base.0 + 4].0, PERM_READ | PERM_WRITE);
+        assert!(mem.read(VAddr(base.0 + 4), &mut buf[..4]).is_ok());
+        assert_eq!(&buf[..4], b"asdf");
+        assert!(!CHECKED ||
+                mem.read(VAddr(base.0 + 3), &mut buf[..4]).is_err());
+        assert!(!CHECKED ||
+                mem.read(VAddr(base.0 + 5), &mut buf[..4]).is_err());
+
+        // Writing doesn't make write-only memory without `PERM_RAW` readable
+        mem.set_permissions(base, 4, Perm(PERM_WRITE)).unwrap();
+        mem.write(base, b"asdf").unwrap();
+        assert!(!CHECKED || mem.read(base, &mut buf[..1]).is_err());
     }
 
     #[test]
-    #[should_panic]
-    fn reset_permissions() {
+    fn write_batch() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let addr = base.allocate(DIRTY_BLOCK_SIZE * 2).unwrap();
+        let mut mem = base.fork();
+
+        let writes: Vec<(VAddr, &[u8])> = (0..64)
+            .map(|ii| (VAddr(addr.0 + ii * 16), &b"asdf"[..]))
+            .collect();
+        mem.write_batch(&writes).unwrap();
+        assert_eq!(mem.dirty_indexes, vec![0]);
+        let mut buf = [0; 4];
+        mem.read(VAddr(addr.0 + 63 * 16), &mut buf).unwrap();
+        assert_eq!(&buf, b"asdf");
+
+        // Writes jumping between blocks still mark each of them once
+        let mut mem = base.fork();
+        let writes: Vec<(VAddr, &[u8])> = (0..64).rev()
+            .map(|ii| (VAddr(addr.0 + ii * 128), &b"asdf"[..]))
+            .collect();
+        mem.write_batch(&writes).unwrap();
+        assert_eq!(mem.dirty_indexes, vec![0, 1]);
+
+        // A single bad target fails the whole batch before anything's written
+        let bad = VAddr(addr.0 + DIRTY_BLOCK_SIZE);
+        mem.set_permissions(bad, 4, Perm(PERM_READ)).unwrap();
+        let writes: [(VAddr, &[u8]); 2] = [
+            (VAddr(bad.0 + 8), b"qwer"),
+            (bad,              b"qwer"),
+        ];
+        assert!(mem.write_batch(&writes).is_err());
+        assert_eq!(mem.memory[bad.0 + 8], 0);
+        assert_eq!(mem.dirty_indexes, vec![0, 1]);
+    }
+
+    #[test]
+    fn permission_spans() {
         let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
-        let base = mem.allocate(DIRTY_BLOCK_SIZE).unwrap();
-        {
-            let mut buf = [0; DIRTY_BLOCK_SIZE];
-            let mut new_mem = mem.fork();
+        let base = mem.allocate(32).unwrap();
+        mem.write(base, &[0; 8]).unwrap();
+        mem.set_permissions(VAddr(base.0 + 8), 8, Perm(PERM_READ | PERM_EXEC))
+            .unwrap();
 
-            // Here we write to the memory, so we set `PERM_READ`.
-            // Consecutive read operations shouldn't panic.
-            assert!(new_mem.write(base, &buf).is_some());
-            assert!(new_mem.read(base, &mut buf).is_some());
+        assert_eq!(mem.readable_span(base), 16);
+        assert_eq!(mem.readable_span(VAddr(base.0 + 12)), 4);
+        assert_eq!(mem.readable_span(VAddr(base.0 + 16)), 0);
+        assert_eq!(mem.writable_span(base), 8);
+        assert_eq!(mem.writable_span(VAddr(base.0 + 16)), 16);
+        assert_eq!(mem.executable_span(base), 0);
+        assert_eq!(mem.executable_span(VAddr(base.0 + 8)), 8);
 
-            // When the memory is reset, permissions are reset as well.
-            // Since we haven't written to the memory yet, we can't read it.
-            new_mem.reset(&mem);
-            new_mem.read(base, &mut buf).unwrap();
+        // Spans stop at the end of memory
+        let end = VAddr(DIRTY_BLOCK_SIZE - 4);
+        mem.set_permissions(end, 4, Perm(PERM_READ)).unwrap();
+        assert_eq!(mem.readable_span(end), 4);
+        assert_eq!(mem.readable_span(VAddr(DIRTY_BLOCK_SIZE)), 0);
+    }
+
+    #[test]
+    fn mmu_error_variants() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(16).unwrap();
+
+        // Writing to unmapped memory lacks `PERM_WRITE`
+        let unmapped = VAddr(base.0 + 16);
+        if CHECKED {
+            assert_eq!(mem.write(unmapped, b"a"),
+                       Err(MmuError::PermissionDenied {
+                           addr:    unmapped,
+                           missing: Perm(PERM_WRITE),
+                       }));
+        }
+
+        // Reading allocated but unwritten memory lacks `PERM_READ`
+        mem.write(base, b"asdf").unwrap();
+        let mut buf = [0; 8];
+        if CHECKED {
+            assert_eq!(mem.read(base, &mut buf),
+                       Err(MmuError::PermissionDenied {
+                           addr:    VAddr(base.0 + 4),
+                           missing: Perm(PERM_READ),
+                       }));
+        }
+
+        assert_eq!(mem.write(VAddr(DIRTY_BLOCK_SIZE - 2), b"asdf"),
+                   Err(MmuError::OutOfBounds {
+                       addr: VAddr(DIRTY_BLOCK_SIZE - 2),
+                       len:  4,
+                   }));
+        assert_eq!(mem.read(VAddr(usize::MAX), &mut buf),
+                   Err(MmuError::Overflow));
+        assert_eq!(mem.allocate(DIRTY_BLOCK_SIZE), Err(MmuError::OutOfMemory));
+        assert_eq!(mem.mremap(VAddr(base.0 + 1), 16, 32, true),
+                   Err(MmuError::NotAllocated { addr: VAddr(base.0 + 1) }));
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let code = base.allocate(16).unwrap();
+        base.write(code, b"\x90\x90\x90\x90").unwrap();
+        base.finalize_code(code, 16).unwrap();
+        base.full_verify = true;
+
+        let mut mmu = base.fork();
+        let bp = VAddr(code.0 + 2);
+        assert_eq!(mmu.set_breakpoint(bp, 0xcc), Some(0x90));
+        assert_eq!(mmu.set_breakpoint(bp, 0xcd), Some(0x90));
+        assert_eq!(mmu.set_breakpoint(VAddr(usize::MAX), 0xcc), None);
+        assert_eq!(base.memory[bp.0], 0x90);
+
+        // The breakpoint survives a reset against the base
+        mmu.reset(&base);
+        assert_eq!(mmu.memory[bp.0], 0xcd);
+        mmu.reset_to_zero();
+        assert_eq!(mmu.memory[bp.0], 0xcd);
+        assert_eq!(mmu.clear_breakpoint(bp), Some(()));
+        assert_eq!(mmu.memory[bp.0], 0x90);
+        assert_eq!(mmu.clear_breakpoint(bp), None);
+
+        // Forks of a base with breakpoints restore the originals correctly
+        base.set_breakpoint(bp, 0xcc).unwrap();
+        let mut mmu = base.fork();
+        mmu.write_unchecked_perm(code, &[0x90; 4]).unwrap();
+        mmu.reset(&base);
+        assert_eq!(mmu.clear_breakpoint(bp), Some(()));
+        assert_eq!(mmu.memory[bp.0], 0x90);
+    }
+
+    #[test]
+    fn fork_cow() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE * 16384);
+        base.set_default_alloc_perm(Perm(PERM_READ | PERM_WRITE));
+        let addr = base.allocate(DIRTY_BLOCK_SIZE * 3).unwrap();
+        base.write(VAddr(addr.0 + DIRTY_BLOCK_SIZE - 2), b"asdf").unwrap();
+        let base = base.freeze();
+
+        // Forking a 64 MiB MMU and writing a single block only copies that
+        // block, no matter how many times it's done
+        for _ in 0..256 {
+            let mut mmu = base.fork_cow();
+            assert_eq!(mmu.cow_copied_blocks(), 0);
+
+            mmu.write(VAddr(addr.0 + 1), b"x").unwrap();
+            assert_eq!(mmu.cow_copied_blocks(), 1);
+
+            // Reads spanning copied and shared blocks see both
+            let mut buf = [0; 4];
+            mmu.read(VAddr(addr.0 + DIRTY_BLOCK_SIZE - 2), &mut buf).unwrap();
+            assert_eq!(&buf, b"asdf");
+            mmu.read(addr, &mut buf[..2]).unwrap();
+            assert_eq!(&buf[..2], b"\0x");
+
+            // Resetting drops the copy again
+            mmu.reset(&base);
+            assert_eq!(mmu.cow_copied_blocks(), 0);
+            mmu.read(addr, &mut buf[..2]).unwrap();
+            assert_eq!(&buf[..2], b"\0\0");
+        }
+
+        // Writes across blocks and resets against other MMUs copy as needed
+        let mut mmu = base.fork_cow();
+        mmu.write(VAddr(addr.0 + DIRTY_BLOCK_SIZE - 2), b"qwer").unwrap();
+        assert_eq!(mmu.cow_copied_blocks(), 2);
+        let eager = mmu.fork();
+        mmu.reset(&base.fork());
+
+        let mut buf = [0; 4];
+        mmu.read(VAddr(addr.0 + DIRTY_BLOCK_SIZE - 2), &mut buf).unwrap();
+        assert_eq!(&buf, b"asdf");
+        eager.read(VAddr(addr.0 + DIRTY_BLOCK_SIZE - 2), &mut buf).unwrap();
+        assert_eq!(&buf, b"qwer");
+    }
+
+    #[test]
+    fn read_write_field() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let base = mem.allocate(32).unwrap();
+
+        for (width, value) in [(1, 0x12), (2, 0x1234), (4, 0x12345678),
+                               (8, 0x123456789abcdef0)] {
+            for endian in [Endian::Little, Endian::Big] {
+                mem.write_field(base, 8, width, endian, value).unwrap();
+                assert_eq!(mem.read_field(base, 8, width, endian),
+                           Some(value));
+            }
+        }
+
+        // The byte order of the field is respected
+        mem.write_field(base, 4, 2, Endian::Big, 0xaabb).unwrap();
+        assert_eq!(mem.read_field(base, 4, 2, Endian::Little), Some(0xbbaa));
+        assert_eq!(mem.read_field(base, 4, 1, Endian::Little), Some(0xaa));
+
+        // Unsupported widths, values which don't fit and unreadable fields
+        assert_eq!(mem.read_field(base, 0, 3, Endian::Little), None);
+        assert_eq!(mem.write_field(base, 0, 16, Endian::Little, 0), None);
+        assert_eq!(mem.write_field(base, 0, 1, Endian::Little, 0x100), None);
+        if CHECKED {
+            assert_eq!(mem.read_field(base, 24, 8, Endian::Little), None);
+        }
+        assert_eq!(mem.read_field(base, usize::MAX, 1, Endian::Little), None);
+    }
+
+    #[test]
+    fn typed_accessors() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let base = mem.allocate(DIRTY_BLOCK_SIZE * 2).unwrap();
+
+        mem.write_u8(base, 0xab).unwrap();
+        assert_eq!(mem.read_u8(base), Ok(0xab));
+        for endian in [Endian::Little, Endian::Big] {
+            mem.write_u16(base, endian, 0x1234).unwrap();
+            assert_eq!(mem.read_u16(base, endian), Ok(0x1234));
+            mem.write_u32(base, endian, 0x12345678).unwrap();
+            assert_eq!(mem.read_u32(base, endian), Ok(0x12345678));
+            mem.write_u64(base, endian, 0x123456789abcdef0).unwrap();
+            assert_eq!(mem.read_u64(base, endian), Ok(0x123456789abcdef0));
+        }
+        assert_eq!(mem.read_u16(base, Endian::Big), Ok(0x1234));
+        assert_eq!(mem.read_u16(base, Endian::Little), Ok(0x3412));
+
+        // A value straddling two blocks dirties and exposes both of them
+        let straddle = VAddr(base.0 + DIRTY_BLOCK_SIZE - 2);
+        mem.write_u32(straddle, Endian::Big, 0xdeadbeef).unwrap();
+        assert_eq!(mem.read_u32(straddle, Endian::Big), Ok(0xdeadbeef));
+        assert_eq!(mem.read_u16(VAddr(straddle.0 + 2), Endian::Big),
+                   Ok(0xbeef));
+        assert_eq!(mem.dirty_indexes, vec![0, 1]);
+
+        // Partially unreadable values still fault
+        assert!(!CHECKED ||
+                mem.read_u32(VAddr(straddle.0 + 3), Endian::Big).is_err());
+        assert!(mem.read_u16(VAddr(DIRTY_BLOCK_SIZE * 2 - 1),
+                             Endian::Little).is_err());
+        assert!(mem.write_u16(VAddr(DIRTY_BLOCK_SIZE * 2 - 1),
+                              Endian::Little, 0).is_err());
+    }
+
+    #[test]
+    fn overlay_stack() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let addr = mem.allocate(8).unwrap();
+        mem.write(addr, b"base").unwrap();
+        let mut buf = [0; 4];
+
+        // Rolled back overlays undo their writes and allocations
+        mem.push_overlay();
+        mem.write(addr, b"frm1").unwrap();
+        let local = mem.allocate(16).unwrap();
+        assert_eq!(mem.pop_overlay(false), Some(()));
+        mem.read(addr, &mut buf).unwrap();
+        assert_eq!(&buf, b"base");
+        assert!(!CHECKED || mem.read(local, &mut buf).is_err());
+        assert_eq!(mem.allocate(16).unwrap(), local);
+
+        // Committed overlays fold into the enclosing one
+        mem.push_overlay();
+        mem.push_overlay();
+        mem.write(addr, b"frm2").unwrap();
+        assert_eq!(mem.pop_overlay(true), Some(()));
+        mem.read(addr, &mut buf).unwrap();
+        assert_eq!(&buf, b"frm2");
+        assert_eq!(mem.pop_overlay(false), Some(()));
+        mem.read(addr, &mut buf).unwrap();
+        assert_eq!(&buf, b"base");
+
+        // Checkpoints aren't popped as overlays
+        assert_eq!(mem.pop_overlay(false), None);
+        mem.checkpoint("named");
+        assert_eq!(mem.pop_overlay(true), None);
+        assert_eq!(mem.rollback("named"), Ok(()));
+    }
+
+    #[test]
+    fn free() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let a = mem.allocate(32).unwrap();
+        let b = mem.allocate(64).unwrap();
+        let c = mem.allocate(32).unwrap();
+        mem.write(a, b"asdf").unwrap();
+
+        // Freed memory faults on every kind of access
+        mem.free(a, 32).unwrap();
+        let mut buf = [0; 4];
+        if CHECKED {
+            assert!(matches!(mem.read(a, &mut buf),
+                             Err(MmuError::PermissionDenied { .. })));
+        }
+        assert!(!CHECKED || mem.write(a, b"a").is_err());
+        assert_eq!(mem.check_leaks(), vec![(b, 64), (c, 32)]);
+
+        // Double frees and frees outside of allocations are rejected
+        let not_allocated = Err(MmuError::NotAllocated { addr: a });
+        assert_eq!(mem.free(a, 32), not_allocated);
+        assert_eq!(mem.free(VAddr(b.0 + 32), 48),
+                   Err(MmuError::NotAllocated { addr: VAddr(b.0 + 32) }));
+        assert!(mem.free(VAddr(DIRTY_BLOCK_SIZE / 2), 8).is_err());
+
+        // Freeing part of an allocation keeps the rest of it alive
+        mem.free(VAddr(b.0 + 32), 32).unwrap();
+        assert!(mem.write(b, b"b").is_ok());
+        assert!(!CHECKED || mem.write(VAddr(b.0 + 32), b"b").is_err());
+
+        // Freed allocations leave holes behind, the last one joining the
+        // space left for the bump allocator
+        mem.free(c, 32).unwrap();
+        assert_eq!(mem.largest_free(), mem.free_space() + 32);
+        mem.free(b, 64).unwrap();
+        assert!(mem.check_leaks().is_empty());
+        assert_eq!(mem.largest_free(), DIRTY_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn permission_metrics() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        assert_eq!(mem.permission_region_count(), 0);
+        assert_eq!(mem.distinct_permissions(), 0);
+
+        // RW, RX, RW, a gap, and RW again
+        let rw = Perm(PERM_READ | PERM_WRITE);
+        mem.allocate_perm(32, rw).unwrap();
+        let code = mem.allocate(32).unwrap();
+        mem.finalize_code(code, 32).unwrap();
+        mem.allocate_perm(32, rw).unwrap();
+        mem.allocate_perm(32, Perm(0)).unwrap();
+        mem.allocate_perm(32, rw).unwrap();
+
+        assert_eq!(mem.permission_region_count(), 4);
+        assert_eq!(mem.distinct_permissions(), 2);
+    }
+
+    #[test]
+    fn serialize_roundtrip() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let data = mem.allocate(64).unwrap();
+        let code = mem.allocate(16).unwrap();
+        mem.write(data, b"some data").unwrap();
+        mem.write(code, b"\xc3").unwrap();
+        mem.finalize_code(code, 16).unwrap();
+
+        let snapshot = mem.serialize();
+        let loaded = Mmu::deserialize(&snapshot).unwrap();
+        assert_eq!(loaded.memory, mem.memory);
+        assert_eq!(loaded.permissions, mem.permissions);
+        assert_eq!(loaded.alloc_base, mem.alloc_base);
+        assert!(loaded.dirty_indexes.is_empty());
+        assert!(loaded.dirty_bitmap.iter().all(|&x| x == 0));
+
+        // Truncated snapshots and other versions are rejected
+        assert!(Mmu::deserialize(&snapshot[..snapshot.len() - 1]).is_none());
+        assert!(Mmu::deserialize(&snapshot[..8]).is_none());
+        let mut future = snapshot.clone();
+        future[4] += 1;
+        assert!(Mmu::deserialize(&future).is_none());
+
+        // A bogus length in the header doesn't overflow
+        let mut huge = snapshot[..SNAPSHOT_HEADER_SIZE].to_vec();
+        huge[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Mmu::deserialize(&huge).is_none());
+    }
+
+    #[test]
+    fn write_sealed() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let code = mem.allocate_perm(16, Perm(0)).unwrap();
+        let rx = Perm(PERM_READ | PERM_EXEC);
+        mem.write_sealed(code, b"\x90\xc3", rx).unwrap();
+
+        // The region is readable and executable, but no longer writable
+        let mut buf = [0; 2];
+        mem.read(code, &mut buf).unwrap();
+        assert_eq!(&buf, b"\x90\xc3");
+        mem.fetch(code, &mut buf).unwrap();
+        assert_eq!(&buf, b"\x90\xc3");
+        assert!(!CHECKED || mem.write(code, b"\xcc").is_err());
+        assert_eq!(mem.permissions[code.0..code.0 + 2], [rx, rx]);
+        assert_eq!(mem.permissions[code.0 + 2], Perm(0));
+
+        // Sealing as execute-only hides the bytes from reads
+        mem.write_sealed(code, b"\xc3", Perm(PERM_EXEC)).unwrap();
+        assert!(!CHECKED || mem.read(code, &mut buf[..1]).is_err());
+        assert!(mem.fetch(code, &mut buf[..1]).is_ok());
+        assert!(mem.write_sealed(VAddr(DIRTY_BLOCK_SIZE), b"a", rx).is_err());
+    }
+
+    #[test]
+    fn fetch_requires_exec() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let data = mem.allocate_perm(16, Perm(PERM_READ)).unwrap();
+        let code = mem.allocate_perm(16, Perm(PERM_READ | PERM_WRITE |
+                                              PERM_EXEC)).unwrap();
+        let mut buf = [0; 4];
+
+        // Readable but not executable memory can't be fetched from
+        assert_eq!(mem.fetch(data, &mut buf), Err(MmuError::PermissionDenied {
+            addr:    data,
+            missing: Perm(PERM_EXEC),
+        }));
+        assert!(mem.read(data, &mut buf).is_ok());
+
+        mem.write(code, b"\x90\xc3").unwrap();
+        mem.fetch(code, &mut buf[..2]).unwrap();
+        assert_eq!(&buf[..2], b"\x90\xc3");
+
+        // In the strict mode, writing makes the bytes non-executable
+        mem.set_strict_wx(true);
+        mem.write(code, b"\xcc").unwrap();
+        assert!(mem.fetch(code, &mut buf[..1]).is_err());
+        assert!(mem.fetch(VAddr(code.0 + 1), &mut buf[..1]).is_ok());
+        mem.finalize_code(code, 16).unwrap();
+        assert!(mem.fetch(code, &mut buf[..1]).is_ok());
+    }
+
+    #[test]
+    fn null_guard() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        mem.set_null_guard(NULL_GUARD_SIZE).unwrap();
+
+        // Allocations start above the guard
+        let addr = mem.allocate(16).unwrap();
+        assert_eq!(addr, VAddr(NULL_GUARD_SIZE));
+        mem.write(addr, b"asdf").unwrap();
+
+        // Nothing near NULL can be accessed, not even after mapping it
+        let mut buf = [0; 4];
+        assert!(!CHECKED || mem.read(VAddr(0), &mut buf).is_err());
+        assert!(!CHECKED || mem.write(VAddr(0), b"a").is_err());
+        mem.set_permissions(VAddr(0), NULL_GUARD_SIZE + 16,
+                            Perm(PERM_READ | PERM_WRITE)).unwrap();
+        assert!(!CHECKED || mem.write(VAddr(8), b"a").is_err());
+        assert!(!CHECKED ||
+                mem.read(VAddr(NULL_GUARD_SIZE - 1), &mut buf).is_err());
+        assert!(mem.read(addr, &mut buf).is_ok());
+        assert!(mem.set_null_guard(DIRTY_BLOCK_SIZE * 3).is_err());
+    }
+
+    #[test]
+    fn alloc_guard() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        mem.set_default_alloc_perm(Perm(PERM_READ | PERM_WRITE));
+        mem.set_alloc_guard(32);
+        let a = mem.allocate(16).unwrap();
+        let b = mem.allocate(16).unwrap();
+        assert_eq!(b.0 - a.0, 48);
+        mem.write(b, b"b").unwrap();
+
+        // Overflowing the first buffer faults instead of reaching the second
+        if CHECKED {
+            assert_eq!(mem.write(a, &[0x41; 17]),
+                       Err(MmuError::PermissionDenied {
+                           addr:    VAddr(a.0 + 16),
+                           missing: Perm(PERM_WRITE),
+                       }));
         }
+        assert!(!CHECKED || mem.write(VAddr(a.0 + 16 + 31), b"a").is_err());
+        let mut buf = [0; 1];
+        mem.read(b, &mut buf).unwrap();
+        assert_eq!(&buf, b"b");
+
+        // Growing in place keeps the gap in front of the next allocation
+        assert_eq!(mem.mremap(a, 16, 32, false), Err(MmuError::OutOfMemory));
+        assert_eq!(mem.mremap(b, 16, 32, false), Ok(b));
+        assert!(!CHECKED || mem.write(VAddr(b.0 + 32), b"a").is_err());
+    }
+
+    #[test]
+    fn take_dirty_indexes() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        let addr = mem.allocate(DIRTY_BLOCK_SIZE * 4).unwrap();
+        let mut mem = mem.fork();
+        mem.write(VAddr(addr.0 + DIRTY_BLOCK_SIZE * 2), b"a").unwrap();
+        mem.write(addr, b"a").unwrap();
+
+        assert_eq!(mem.take_dirty_indexes(), vec![2, 0]);
+        assert!(mem.dirty_indexes.is_empty());
+        assert!(mem.sample_dirty().iter().all(|&dirty| !dirty));
+
+        // Blocks get tracked again once written to after taking them
+        mem.write(addr, b"b").unwrap();
+        assert_eq!(mem.take_dirty_indexes(), vec![0]);
+        assert!(mem.take_dirty_indexes().is_empty());
+    }
+
+    #[test]
+    fn load_rodata() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let rodata = VAddr(DIRTY_BLOCK_SIZE - 4);
+        mem.load(rodata, b"constant", Perm(PERM_READ)).unwrap();
+
+        // The segment reads back, but can't be written to
+        let mut buf = [0; 8];
+        mem.read(rodata, &mut buf).unwrap();
+        assert_eq!(&buf, b"constant");
+        assert!(!CHECKED || mem.write(rodata, b"C").is_err());
+        assert_eq!(mem.permissions[rodata.0..rodata.0 + 8],
+                   [Perm(PERM_READ); 8]);
+        assert_eq!(mem.dirty_indexes, vec![0, 1]);
+
+        // Loading over read-only memory reinitializes it
+        mem.load(rodata, b"C", Perm(PERM_READ | PERM_EXEC)).unwrap();
+        mem.read(rodata, &mut buf[..2]).unwrap();
+        assert_eq!(&buf[..2], b"Co");
+        assert!(mem.load(VAddr(DIRTY_BLOCK_SIZE * 2 - 4), &buf,
+                         Perm(PERM_READ)).is_err());
+    }
+
+    #[test]
+    fn ring_buffer() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let ring = mem.map_ring(8).unwrap();
+        mem.ring_write(&ring, 0, b"01234567").unwrap();
+
+        // Writing past the end wraps around to the start
+        mem.ring_write(&ring, 14, b"abcd").unwrap();
+        let mut buf = [0; 8];
+        mem.read(ring.base(), &mut buf).unwrap();
+        assert_eq!(&buf, b"cd2345ab");
+        mem.ring_read(&ring, 6, &mut buf).unwrap();
+        assert_eq!(&buf, b"abcd2345");
+
+        // Accesses longer than the ring keep wrapping
+        let mut long = [0; 12];
+        mem.ring_read(&ring, 0, &mut long).unwrap();
+        assert_eq!(&long, b"cd2345abcd23");
+
+        // The permissions of the ring are still honored
+        mem.set_permissions(ring.base(), 1, Perm(PERM_READ)).unwrap();
+        assert!(mem.ring_write(&ring, 7, b"xy").is_err());
+        mem.ring_read(&ring, 7, &mut buf[..1]).unwrap();
+        assert_eq!(buf[0], b'b');
+        assert_eq!(mem.map_ring(0), Err(MmuError::InvalidArgument));
+        assert_eq!(Ring::new(ring.base(), 0), Err(MmuError::InvalidArgument));
+        assert_eq!(Ring::new(ring.base(), 8), Ok(ring));
+    }
+
+    #[test]
+    fn dirty_stats() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        let addr = base.allocate(DIRTY_BLOCK_SIZE * 4).unwrap();
+        let mut mem = base.fork();
+        assert_eq!(mem.dirty_block_count(), 0);
+
+        mem.write(addr, b"a").unwrap();
+        mem.write(VAddr(addr.0 + DIRTY_BLOCK_SIZE * 3), b"a").unwrap();
+        mem.write(VAddr(addr.0 + 1), b"a").unwrap();
+        assert_eq!(mem.dirty_block_count(), 2);
+        assert_eq!(mem.dirty_byte_estimate(), DIRTY_BLOCK_SIZE * 2);
+
+        mem.reset(&base);
+        assert_eq!(mem.dirty_block_count(), 0);
+        assert_eq!(mem.dirty_byte_estimate(), 0);
+        assert_eq!(mem.total_dirty_resets(), 1);
+        mem.reset(&base);
+        assert_eq!(mem.total_dirty_resets(), 2);
+    }
+
+    #[test]
+    fn permissions_changed_since() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let rw = Perm(PERM_READ | PERM_WRITE);
+        let addr = mem.allocate_perm(64, rw).unwrap();
+        let snap = mem.snapshot_permissions();
+        assert!(mem.permissions_changed_since(&snap).is_empty());
+
+        // An mprotect in the middle and a fresh allocation
+        let rx = Perm(PERM_READ | PERM_EXEC);
+        mem.set_permissions(VAddr(addr.0 + 16), 16, rx).unwrap();
+        let fresh = mem.allocate_perm(16, rw).unwrap();
+        assert_eq!(mem.permissions_changed_since(&snap), vec![
+            (VAddr(addr.0 + 16), 16, rw, rx),
+            (fresh, 16, Perm(0), rw),
+        ]);
+
+        // Changing permissions back drops the transition
+        mem.set_permissions(VAddr(addr.0 + 16), 16, rw).unwrap();
+        assert_eq!(mem.permissions_changed_since(&snap),
+                   vec![(fresh, 16, Perm(0), rw)]);
+    }
+
+    #[test]
+    fn fill() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        let addr = mem.allocate(DIRTY_BLOCK_SIZE * 4).unwrap();
+        let mut mem = mem.fork();
+
+        // Fill two partial blocks and a whole one in between
+        let start = VAddr(addr.0 + DIRTY_BLOCK_SIZE - 8);
+        mem.fill(start, 0x41, DIRTY_BLOCK_SIZE + 16).unwrap();
+        let mut buf = vec![0; DIRTY_BLOCK_SIZE + 16];
+        mem.read(start, &mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 0x41));
+        assert_eq!(mem.dirty_indexes, vec![0, 1, 2]);
+        assert!(!CHECKED ||
+                mem.read(VAddr(start.0 - 1), &mut buf[..1]).is_err());
+
+        // A partially unwritable range faults without filling anything
+        mem.set_permissions(VAddr(start.0 + 4), 1, Perm(PERM_READ)).unwrap();
+        assert!(mem.fill(start, 0x42, 8).is_err());
+        mem.read(start, &mut buf[..8]).unwrap();
+        assert_eq!(&buf[..8], &[0x41; 8]);
+        assert!(mem.fill(VAddr(DIRTY_BLOCK_SIZE * 4 - 1), 0, 2).is_err());
+    }
+
+    #[test]
+    fn reset_is_idempotent() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        let addr = mem.allocate(DIRTY_BLOCK_SIZE * 4).unwrap();
+        let base = mem.fork();
+        let mut mem = base.fork();
+
+        mem.fill(VAddr(addr.0 + 16), 0x41, DIRTY_BLOCK_SIZE * 2).unwrap();
+        mem.reset(&base);
+        let first = mem.reset_totals();
+        assert_eq!(first.blocks, 3);
+        assert_eq!(mem.dirty_popcount(), 0);
+
+        // The second reset has nothing left to restore
+        mem.reset(&base);
+        let second = mem.reset_totals();
+        assert_eq!(second.resets, 2);
+        assert_eq!(second.blocks, first.blocks);
+        assert_eq!(second.copies, first.copies);
+        assert_eq!(second.bytes, first.bytes);
+    }
+
+    #[test]
+    fn shared_base_across_threads() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE * 8);
+        let addr = base.allocate(DIRTY_BLOCK_SIZE * 8).unwrap();
+        base.fill(addr, 0x41, DIRTY_BLOCK_SIZE * 8).unwrap();
+        let base = Arc::new(base.fork());
+
+        std::thread::scope(|scope| {
+            for thread in 0..4u8 {
+                let base = Arc::clone(&base);
+                scope.spawn(move || {
+                    let mut mmu = base.fork_cow();
+                    let block = VAddr(addr.0 + thread as usize *
+                                      DIRTY_BLOCK_SIZE);
+                    for _ in 0..8 {
+                        mmu.fill(block, thread, 64).unwrap();
+                        assert_eq!(mmu.read_u8(block).unwrap(), thread);
+                        assert_eq!(mmu.cow_copied_blocks(), 1);
+
+                        mmu.reset(&base);
+                        assert_eq!(mmu.read_u8(block).unwrap(), 0x41);
+                        assert_eq!(mmu.cow_copied_blocks(), 0);
+                    }
+                });
+            }
+        });
+
+        // The base never saw any of the writes
+        let mut buf = vec![0; DIRTY_BLOCK_SIZE * 8];
+        base.read(addr, &mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 0x41));
+    }
+
+    #[test]
+    fn alloc_scope() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let outer = mem.allocate(16).unwrap();
+
+        let scratch = mem.alloc_scope(|mem| {
+            let scratch = mem.allocate(64).unwrap();
+            mem.write(scratch, b"scratch!").unwrap();
+            assert!(mem.allocations.contains_key(&scratch));
+            scratch
+        });
+
+        // The scratch space is inaccessible and handed out again
+        let mut buf = [0; 8];
+        assert!(!CHECKED || mem.read(scratch, &mut buf).is_err());
+        assert!(!CHECKED || mem.write(scratch, b"x").is_err());
+        assert!(!mem.allocations.contains_key(&scratch));
+        assert_eq!(mem.allocate(8).unwrap(), scratch);
+
+        // Allocations from before the scope are kept
+        assert!(mem.write(outer, b"outer").is_ok());
+    }
+
+    #[test]
+    fn modify_permissions() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let addr = mem.allocate_perm(16, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
+
+        // Adding `PERM_EXEC` keeps the other bits
+        mem.modify_permissions(addr, 16, Perm(PERM_EXEC), PermOp::Add)
+            .unwrap();
+        assert!(mem.permissions[addr.0..addr.0 + 16].iter()
+                .all(|&x| x == Perm(PERM_READ | PERM_WRITE | PERM_EXEC)));
+
+        // Removing `PERM_WRITE` from a part makes writes to it fault
+        mem.modify_permissions(VAddr(addr.0 + 8), 8, Perm(PERM_WRITE),
+                               PermOp::Remove).unwrap();
+        assert!(mem.write(addr, b"asdfasdf").is_ok());
+        assert!(!CHECKED || mem.write(VAddr(addr.0 + 8), b"x").is_err());
+        assert_eq!(mem.permissions[addr.0 + 8], Perm(PERM_READ | PERM_EXEC));
+
+        mem.modify_permissions(addr, 16, Perm(PERM_READ), PermOp::Replace)
+            .unwrap();
+        assert!(mem.permissions[addr.0..addr.0 + 16].iter()
+                .all(|&x| x == Perm(PERM_READ)));
+        assert!(mem.modify_permissions(VAddr(usize::MAX), 2, Perm(0),
+                                       PermOp::Add).is_err());
+    }
+
+    #[test]
+    fn diff() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        let addr = base.allocate(DIRTY_BLOCK_SIZE * 4).unwrap();
+        base.fill(addr, 0, DIRTY_BLOCK_SIZE * 4).unwrap();
+        let base = base.fork();
+        let mut mem = base.fork();
+        assert!(mem.diff(&base).is_empty());
+
+        // One range spans a block boundary, the other is left alone
+        let first = VAddr(addr.0 + DIRTY_BLOCK_SIZE - 4);
+        let second = VAddr(addr.0 + DIRTY_BLOCK_SIZE * 3 + 100);
+        mem.write(first, b"asdfasdf").unwrap();
+        mem.write(second, b"qwer").unwrap();
+        assert_eq!(mem.diff(&base), vec![(first, 8), (second, 4)]);
+
+        // Rewriting the original bytes isn't a difference
+        mem.write(second, &[0; 4]).unwrap();
+        assert_eq!(mem.diff(&base), vec![(first, 8)]);
+    }
+
+    #[test]
+    fn vectored_access() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let addr = mem.allocate(DIRTY_BLOCK_SIZE * 2).unwrap();
+
+        // A write spanning two blocks
+        let start = VAddr(addr.0 + DIRTY_BLOCK_SIZE - 4);
+        mem.write_vectored(start, &[b"asd", b"", b"fqwer"]).unwrap();
+        assert_eq!(mem.dirty_indexes, vec![0, 1]);
+
+        let (mut a, mut b) = ([0; 2], [0; 6]);
+        mem.read_vectored(start, &mut [&mut a, &mut b]).unwrap();
+        assert_eq!((&a, &b), (b"as", b"dfqwer"));
+
+        // Part of the combined range is unwritable, so nothing is written
+        mem.set_permissions(VAddr(start.0 + 10), 1, Perm(PERM_READ)).unwrap();
+        assert!(mem.write_vectored(start, &[b"zzzz", b"zzzzzzz"]).is_err());
+        let mut buf = [0; 8];
+        mem.read(start, &mut buf).unwrap();
+        assert_eq!(&buf, b"asdfqwer");
+
+        // Nor read, if it's not readable
+        assert!(mem.read_vectored(start, &mut [&mut a, &mut [0; 8]])
+                .is_err());
+        assert_eq!(&a, b"as");
+    }
+
+    #[test]
+    fn unchecked_access() {
+        let mut checked = Mmu::new(DIRTY_BLOCK_SIZE * 2);
+        let addr = checked.allocate(DIRTY_BLOCK_SIZE * 2).unwrap();
+        let mut unchecked = checked.fork();
+
+        // Valid accesses behave the same either way
+        let start = VAddr(addr.0 + DIRTY_BLOCK_SIZE - 4);
+        checked.write(start, b"asdfqwer").unwrap();
+        unchecked.write_unchecked(start, b"asdfqwer").unwrap();
+        assert_eq!(checked.dirty_indexes, unchecked.dirty_indexes);
+        assert!(checked.first_divergence(&unchecked).is_none());
+
+        let (mut a, mut b) = ([0; 8], [0; 8]);
+        checked.read(start, &mut a).unwrap();
+        unchecked.read_unchecked(start, &mut b).unwrap();
+        assert_eq!(a, b);
+
+        // Only the bounds are checked
+        assert!(unchecked.read_unchecked(VAddr(0), &mut b).is_ok());
+        assert!(unchecked.write_unchecked(VAddr(DIRTY_BLOCK_SIZE * 2), b"x")
+                .is_err());
+    }
+
+    #[test]
+    fn allocate_aligned() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        let first = mem.allocate(24).unwrap();
+
+        // A page aligned allocation leaves the padding unpermissioned
+        let page = mem.allocate_aligned(64, DIRTY_BLOCK_SIZE).unwrap();
+        assert_eq!(page.0 % DIRTY_BLOCK_SIZE, 0);
+        assert!(page.0 > first.0);
+        let pad = first.0 + align(24);
+        assert!(mem.permissions[pad..page.0].iter().all(|&x| x == Perm(0)));
+        assert!(!CHECKED || mem.write(VAddr(pad), b"x").is_err());
+        assert!(mem.write(page, b"asdf").is_ok());
+
+        // Small alignments don't pad at all
+        let next = mem.allocate_aligned(8, 4).unwrap();
+        assert_eq!(next.0, page.0 + 64);
+        let simd = mem.allocate_aligned(32, 64).unwrap();
+        assert_eq!(simd.0 % 64, 0);
+
+        assert_eq!(mem.allocate_aligned(8, 24), Err(MmuError::InvalidArgument));
+        assert_eq!(mem.allocate_aligned(8, 0), Err(MmuError::InvalidArgument));
+    }
+
+    #[test]
+    fn free_reports_live_children() {
+        let mut mem = Mmu::new(DIRTY_BLOCK_SIZE);
+        let arena = mem.allocate(256).unwrap();
+        let a = mem.suballocate(arena, 0, 32).unwrap();
+        let b = mem.suballocate(arena, 64, 16).unwrap();
+
+        // Freeing a child drops it from its parent
+        assert_eq!(mem.free(a, 32), Ok(Vec::new()));
+        assert_eq!(mem.children(arena), Some(&[(b, 16)][..]));
+
+        // Freeing the parent reports the children still alive
+        assert_eq!(mem.free(arena, 256), Ok(vec![(b, 16)]));
+        assert_eq!(mem.children(arena), None);
+        assert!(mem.free(b, 16).is_err());
+    }
+
+    #[test]
+    fn fork_budget() {
+        let mut base = Mmu::new(DIRTY_BLOCK_SIZE * 4);
+        let addr = base.allocate(DIRTY_BLOCK_SIZE * 4).unwrap();
+        let base = Arc::new(base);
+        let mut fork = base.fork_cow();
+        fork.set_fork_budget(Some(DIRTY_BLOCK_SIZE * 2));
+
+        // Writes to blocks which were already copied are free
+        fork.write(addr, b"a").unwrap();
+        fork.write(VAddr(addr.0 + 1), b"a").unwrap();
+        assert_eq!(fork.fork_budget(), Some(DIRTY_BLOCK_SIZE));
+
+        // A write needing two more copies doesn't fit and writes nothing
+        let across = VAddr(addr.0 + DIRTY_BLOCK_SIZE * 2 - 2);
+        assert_eq!(fork.write(across, b"asdf"),
+                   Err(MmuError::BudgetExceeded { addr: across }));
+        assert_eq!(fork.cow_copied_blocks(), 1);
+
+        // Until the budget runs out
+        let third = VAddr(addr.0 + DIRTY_BLOCK_SIZE * 2);
+        fork.write(VAddr(addr.0 + DIRTY_BLOCK_SIZE), b"a").unwrap();
+        assert_eq!(fork.fork_budget(), Some(0));
+        assert_eq!(fork.write(third, b"a"),
+                   Err(MmuError::BudgetExceeded { addr: third }));
+
+        // Resetting against the parent refunds the copies
+        fork.reset(&base);
+        assert_eq!(fork.fork_budget(), Some(DIRTY_BLOCK_SIZE * 2));
+        assert!(fork.write(third, b"a").is_ok());
+
+        // Everything else copying blocks is held to the budget as well
+        fork.set_fork_budget(Some(0));
+        let last   = VAddr(addr.0 + DIRTY_BLOCK_SIZE * 3);
+        let budget = Err(MmuError::BudgetExceeded { addr: last });
+        assert_eq!(fork.set_permissions(last, 1, Perm(PERM_READ)), budget);
+        assert_eq!(fork.lock_region(last, 1), budget);
+        assert_eq!(fork.set_breakpoint(last, 0xcc), None);
+        assert_eq!(fork.write_batch(&[(third, b"b"), (last, b"b")]), budget);
+        assert_eq!(fork.bytes(third.0, third.0 + 1)[0], b'a');
+        assert_eq!(fork.cow_copied_blocks(), 1);
+
+        // Only the copies which were charged are refunded
+        let mut fork = base.fork_cow();
+        fork.write(addr, b"a").unwrap();
+        fork.set_fork_budget(Some(DIRTY_BLOCK_SIZE));
+        fork.grow_with_perm(DIRTY_BLOCK_SIZE, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
+        fork.write(VAddr(DIRTY_BLOCK_SIZE * 4), b"a").unwrap();
+        assert_eq!(fork.fork_budget(), Some(0));
+        fork.reset(&base);
+        assert_eq!(fork.fork_budget(), Some(0));
     }
 }